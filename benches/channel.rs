@@ -0,0 +1,115 @@
+// -*- mode: rust; -*-
+//
+// This file is part of `scuttlebutt`.
+// Copyright © 2019 Galois, Inc.
+// See LICENSE for licensing information.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+#[cfg(feature = "shm")]
+use scuttlebutt::ShmChannel;
+use scuttlebutt::{cointoss, AbstractChannel, Block, Channel, NetworkConfig, SimChannel};
+use std::io::{BufReader, BufWriter};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+const NSEEDS: usize = 128;
+const NBLOCKS: usize = 1 << 16;
+
+fn cointoss_over(config: NetworkConfig) {
+    let (sender, receiver) = UnixStream::pair().unwrap();
+    let seeds = (0..NSEEDS)
+        .map(|_| rand::random::<Block>())
+        .collect::<Vec<Block>>();
+    let seeds_ = seeds.clone();
+    let handle = std::thread::spawn(move || {
+        let reader = BufReader::new(sender.try_clone().unwrap());
+        let writer = BufWriter::new(sender);
+        let mut channel = SimChannel::new(reader, writer, config);
+        cointoss::send(&mut channel, &seeds_).unwrap();
+    });
+    let reader = BufReader::new(receiver.try_clone().unwrap());
+    let writer = BufWriter::new(receiver);
+    let mut channel = SimChannel::new(reader, writer, config);
+    let out = cointoss::receive(&mut channel, &seeds).unwrap();
+    criterion::black_box(out);
+    handle.join().unwrap();
+}
+
+fn bench_cointoss_lan(c: &mut Criterion) {
+    c.bench_function("cointoss (LAN)", |b| {
+        b.iter(|| cointoss_over(NetworkConfig::lan()));
+    });
+}
+
+fn bench_cointoss_wan(c: &mut Criterion) {
+    c.bench_function("cointoss (WAN)", |b| {
+        b.iter(|| cointoss_over(NetworkConfig::wan()));
+    });
+}
+
+// Send `NBLOCKS` blocks per iteration to a peer that acknowledges each batch.
+fn send_blocks<C: AbstractChannel>(b: &mut criterion::Bencher, channel: &mut C) {
+    let blocks = vec![rand::random::<Block>(); NBLOCKS];
+    b.iter(|| {
+        channel.write_blocks(&blocks).unwrap();
+        channel.flush().unwrap();
+        channel.read_bool().unwrap()
+    });
+}
+
+// Receive batches of `NBLOCKS` blocks until the other party goes away.
+fn receive_blocks<C: AbstractChannel>(channel: &mut C) {
+    let mut blocks = vec![Block::default(); NBLOCKS];
+    while channel.read_blocks(&mut blocks).is_ok() {
+        channel.write_bool(true).unwrap();
+        channel.flush().unwrap();
+    }
+}
+
+fn bench_send_blocks_unix(c: &mut Criterion) {
+    c.bench_function("send blocks (Unix socket)", |b| {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let reader = BufReader::new(receiver.try_clone().unwrap());
+            let writer = BufWriter::new(receiver);
+            receive_blocks(&mut Channel::new(reader, writer));
+        });
+        let reader = BufReader::new(sender.try_clone().unwrap());
+        let writer = BufWriter::new(sender);
+        send_blocks(b, &mut Channel::new(reader, writer));
+        handle.join().unwrap();
+    });
+}
+
+#[cfg(feature = "shm")]
+fn bench_send_blocks_shm(c: &mut Criterion) {
+    c.bench_function("send blocks (shared memory)", |b| {
+        let path =
+            std::env::temp_dir().join(format!("scuttlebutt-bench-{}.shm", std::process::id()));
+        let mut channel = ShmChannel::create(&path, 1 << 20).unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut channel = ShmChannel::open(&path).unwrap();
+            let mut nblocks = 0;
+            while let Ok(view) = channel.read_blocks_view(NBLOCKS - nblocks) {
+                nblocks += criterion::black_box(view).len();
+                if nblocks == NBLOCKS {
+                    channel.write_bool(true).unwrap();
+                    nblocks = 0;
+                }
+            }
+        });
+        send_blocks(b, &mut channel);
+        drop(channel);
+        handle.join().unwrap();
+    });
+}
+
+#[cfg(not(feature = "shm"))]
+fn bench_send_blocks_shm(_: &mut Criterion) {}
+
+criterion_group! {
+    name = channel;
+    config = Criterion::default().warm_up_time(Duration::from_millis(100)).sample_size(10);
+    targets = bench_cointoss_lan, bench_cointoss_wan, bench_send_blocks_unix, bench_send_blocks_shm
+}
+criterion_main!(channel);
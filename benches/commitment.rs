@@ -5,7 +5,8 @@
 // See LICENSE for licensing information.
 
 use criterion::{criterion_group, criterion_main, Criterion};
-use scuttlebutt::commitment::{Commitment, ShaCommitment};
+use scuttlebutt::commitment::{AesCommitment, Commitment, ShaCommitment};
+use scuttlebutt::{AesRng, Block};
 use std::time::Duration;
 
 fn bench_sha_commitment(c: &mut Criterion) {
@@ -19,7 +20,7 @@ fn bench_sha_commitment(c: &mut Criterion) {
 
     c.bench_function("ShaCommitment::commit", |b| {
         let seed = rand::random::<[u8; 32]>();
-        let input = rand::random::<[u8; 32]>();;
+        let input = rand::random::<[u8; 32]>();
         b.iter(|| {
             let mut commit = ShaCommitment::new(seed);
             commit.input(&input);
@@ -27,11 +28,53 @@ fn bench_sha_commitment(c: &mut Criterion) {
             criterion::black_box(c)
         });
     });
+
+    c.bench_function("ShaCommitment::verify", |b| {
+        let input = rand::random::<[u8; 32]>();
+        let (commitment, opening) = ShaCommitment::commit(&input, &mut AesRng::new());
+        b.iter(|| {
+            let ok = ShaCommitment::verify(&commitment, &input, &opening);
+            criterion::black_box(ok)
+        });
+    });
+}
+
+fn bench_aes_commitment(c: &mut Criterion) {
+    c.bench_function("AesCommitment::commit", |b| {
+        let input = rand::random::<Block>();
+        let mut rng = AesRng::new();
+        b.iter(|| {
+            let c = AesCommitment::commit(&input, &mut rng);
+            criterion::black_box(c)
+        });
+    });
+
+    c.bench_function("AesCommitment::commit_batch (1024)", |b| {
+        let inputs = (0..1024)
+            .map(|_| rand::random::<Block>())
+            .collect::<Vec<_>>();
+        let mut rng = AesRng::new();
+        b.iter(|| {
+            let c = AesCommitment::commit_batch(&inputs, &mut rng);
+            criterion::black_box(c)
+        });
+    });
+
+    c.bench_function("AesCommitment::verify_batch (1024)", |b| {
+        let inputs = (0..1024)
+            .map(|_| rand::random::<Block>())
+            .collect::<Vec<_>>();
+        let (commitments, openings) = AesCommitment::commit_batch(&inputs, &mut AesRng::new());
+        b.iter(|| {
+            let ok = AesCommitment::verify_batch(&commitments, &inputs, &openings);
+            criterion::black_box(ok)
+        });
+    });
 }
 
 criterion_group! {
     name = commitment;
     config = Criterion::default().warm_up_time(Duration::from_millis(100));
-    targets = bench_sha_commitment
+    targets = bench_sha_commitment, bench_aes_commitment
 }
 criterion_main!(commitment);
@@ -58,6 +58,19 @@ impl Block {
         }
     }
 
+    /// Multiplication in GF(2^128), with the field defined by the polynomial
+    /// `x^128 + x^7 + x^2 + x + 1`.
+    #[inline]
+    pub fn gfmul(self, rhs: Self) -> Self {
+        let (lo, hi) = self.clmul(rhs);
+        let (lo, hi) = (u128::from(lo), u128::from(hi));
+        // Reduce `hi * x^128` to `hi * (x^7 + x^2 + x + 1)`, which may
+        // overflow by up to seven bits; reduce those once more.
+        let overflow = (hi >> 127) ^ (hi >> 126) ^ (hi >> 121);
+        let hi = hi ^ overflow;
+        Block::from(lo ^ hi ^ (hi << 1) ^ (hi << 2) ^ (hi << 7))
+    }
+
     /// Hash an elliptic curve point `pt` and tweak `tweak`.
     ///
     /// Computes the hash by computing `E_{pt}(tweak)`, where `E` is AES-256.
@@ -354,6 +367,20 @@ mod tests {
         assert_eq!(x, z);
     }
 
+    #[test]
+    fn test_gfmul() {
+        let x = rand::random::<Block>();
+        let y = rand::random::<Block>();
+        let z = rand::random::<Block>();
+        assert_eq!(x.gfmul(y), y.gfmul(x));
+        assert_eq!(x.gfmul(y ^ z), x.gfmul(y) ^ x.gfmul(z));
+        assert_eq!(x.gfmul(Block::from(1u128)), x);
+        // x * x^127 = x^128 = x^7 + x^2 + x + 1.
+        let x = Block::from(2u128);
+        let y = Block::from(1u128 << 127);
+        assert_eq!(x.gfmul(y), Block::from(0x87u128));
+    }
+
     #[test]
     fn test_lsb() {
         let x = rand::random::<Block>();
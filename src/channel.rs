@@ -4,23 +4,82 @@
 // Copyright © 2019 Galois, Inc.
 // See LICENSE for licensing information.
 
+mod control;
+mod cost_channel;
+mod fault_channel;
 mod hash_channel;
+mod record_channel;
+#[cfg(feature = "curve25519-dalek")]
+mod secure_channel;
+mod session_channel;
+#[cfg(all(unix, feature = "shm"))]
+mod shm_channel;
+mod sim_channel;
+mod split_channel;
 mod sync_channel;
 mod track_channel;
 
+pub use control::CancelHandle;
+pub use cost_channel::CostChannel;
+pub use fault_channel::{Fault, FaultChannel};
 pub use hash_channel::HashChannel;
+pub use record_channel::{
+    read_transcript, RecordChannel, ReplayChannel, TranscriptDirection, TranscriptEntry,
+};
+#[cfg(feature = "curve25519-dalek")]
+pub use secure_channel::SecureChannel;
+pub use session_channel::SessionChannel;
+#[cfg(all(unix, feature = "shm"))]
+pub use shm_channel::ShmChannel;
+pub use sim_channel::{NetworkConfig, SimChannel};
+pub use split_channel::{ReadHalf, SyncReadHalf, SyncWriteHalf, WriteHalf};
 pub use sync_channel::SyncChannel;
-pub use track_channel::TrackChannel;
+pub use track_channel::{ChannelStats, SyncTrackChannel, TrackChannel, DEFAULT_PHASE};
 
-use crate::{Block, Block512};
+use crate::{Block, Block512, Error, Result};
+#[cfg(feature = "serde")]
+use bincode::Options;
+use control::{aborted, Control};
 #[cfg(feature = "curve25519-dalek")]
 use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+#[cfg(feature = "curve25519-dalek")]
+use curve25519_dalek::scalar::Scalar;
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Serialize};
+use split_channel::check_halves;
 use std::cell::RefCell;
-use std::io::{Read, Result, Write};
+use std::convert::TryFrom;
+use std::io::{Read, Write};
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The version of the wire format produced by `AbstractChannel`'s helpers.
+///
+/// This must be bumped whenever the encoding of any value sent over a channel
+/// changes.
+pub const WIRE_FORMAT_VERSION: u32 = 1;
 
-/// A trait for managing I/O. `AbstractChannel`s are clonable, and provide basic
-/// read/write capabilities for both common and scuttlebutt-specific types.
+/// The default maximum number of bytes accepted by `AbstractChannel::read_vec`.
+pub const DEFAULT_MAX_VEC_LEN: usize = 1 << 30;
+
+const WIRE_FORMAT_MAGIC: [u8; 4] = *b"SCBT";
+
+// The initial buffer size of `read_vec`, which then at most doubles the buffer
+// for each chunk read, so that a peer announcing a long vector cannot make us
+// allocate much more than it actually sends.
+const VEC_CHUNK_LEN: usize = 1 << 16;
+
+/// A trait for managing I/O. `AbstractChannel`s provide basic read/write
+/// capabilities for both common and scuttlebutt-specific types.
+///
+/// The trait is object safe, so protocols can take a `&mut dyn
+/// AbstractChannel` (or a `Box<dyn AbstractChannel>`) and leave the choice of
+/// transport to run time. It is also implemented for `&mut C` and `Box<C>`, so
+/// such references can be passed to code that is generic over the channel.
+/// Channels that can be shared implement `Clone`, with clones sharing the
+/// underlying transport, so generic code that clones its channel requires
+/// `C: AbstractChannel + Clone`.
 pub trait AbstractChannel {
     /// Read a slice of `u8`s from the channel.
     fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<()>;
@@ -28,10 +87,17 @@ pub trait AbstractChannel {
     fn write_bytes(&mut self, bytes: &[u8]) -> Result<()>;
     /// Flush the channel.
     fn flush(&mut self) -> Result<()>;
-    /// Clone the channel.
-    fn clone(&self) -> Self
-    where
-        Self: Sized;
+    /// Write several slices of `u8`s to the channel, in order.
+    ///
+    /// Channels whose transport supports vectored writes should override this
+    /// to send all the slices with a single `write_vectored` call.
+    #[inline]
+    fn write_bytes_vectored(&mut self, bufs: &[&[u8]]) -> Result<()> {
+        for buf in bufs.iter() {
+            self.write_bytes(buf)?;
+        }
+        Ok(())
+    }
 
     /// Write a `bool` to the channel.
     #[inline(always)]
@@ -48,21 +114,165 @@ pub trait AbstractChannel {
         Ok(data[0] != 0)
     }
 
+    /// Write a `u8` to the channel.
+    #[inline(always)]
+    fn write_u8(&mut self, s: u8) -> Result<()> {
+        self.write_bytes(&[s])?;
+        Ok(())
+    }
+
+    /// Read a `u8` from the channel.
+    #[inline(always)]
+    fn read_u8(&mut self) -> Result<u8> {
+        let mut data = [0u8; 1];
+        self.read_bytes(&mut data)?;
+        Ok(data[0])
+    }
+
+    /// Write a `u16` to the channel, in little-endian byte order.
+    #[inline(always)]
+    fn write_u16(&mut self, s: u16) -> Result<()> {
+        self.write_bytes(&s.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Read a little-endian `u16` from the channel.
+    #[inline(always)]
+    fn read_u16(&mut self) -> Result<u16> {
+        let mut data = [0u8; 2];
+        self.read_bytes(&mut data)?;
+        Ok(u16::from_le_bytes(data))
+    }
+
+    /// Write a `u32` to the channel, in little-endian byte order.
+    #[inline(always)]
+    fn write_u32(&mut self, s: u32) -> Result<()> {
+        self.write_bytes(&s.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Read a little-endian `u32` from the channel.
+    #[inline(always)]
+    fn read_u32(&mut self) -> Result<u32> {
+        let mut data = [0u8; 4];
+        self.read_bytes(&mut data)?;
+        Ok(u32::from_le_bytes(data))
+    }
+
+    /// Write a `u64` to the channel, in little-endian byte order.
+    #[inline(always)]
+    fn write_u64(&mut self, s: u64) -> Result<()> {
+        self.write_bytes(&s.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Read a little-endian `u64` from the channel.
+    #[inline(always)]
+    fn read_u64(&mut self) -> Result<u64> {
+        let mut data = [0u8; 8];
+        self.read_bytes(&mut data)?;
+        Ok(u64::from_le_bytes(data))
+    }
+
+    /// Write a `u128` to the channel, in little-endian byte order.
+    #[inline(always)]
+    fn write_u128(&mut self, s: u128) -> Result<()> {
+        self.write_bytes(&s.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Read a little-endian `u128` from the channel.
+    #[inline(always)]
+    fn read_u128(&mut self) -> Result<u128> {
+        let mut data = [0u8; 16];
+        self.read_bytes(&mut data)?;
+        Ok(u128::from_le_bytes(data))
+    }
+
+    /// Write an `i64` to the channel, in little-endian byte order.
+    #[inline(always)]
+    fn write_i64(&mut self, s: i64) -> Result<()> {
+        self.write_bytes(&s.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Read a little-endian `i64` from the channel.
+    #[inline(always)]
+    fn read_i64(&mut self) -> Result<i64> {
+        let mut data = [0u8; 8];
+        self.read_bytes(&mut data)?;
+        Ok(i64::from_le_bytes(data))
+    }
+
     /// Write a `usize` to the channel.
+    ///
+    /// The value is always sent as a little-endian `u64`, regardless of the
+    /// platform's word size.
     #[inline(always)]
     fn write_usize(&mut self, s: usize) -> Result<()> {
-        let data: [u8; 8] = unsafe { std::mem::transmute(s) };
-        self.write_bytes(&data)?;
-        Ok(())
+        self.write_u64(s as u64)
     }
 
     /// Read a `usize` from the channel.
+    ///
+    /// Fails with `DecodingError` if the value does not fit in a `usize` on
+    /// this platform.
     #[inline(always)]
     fn read_usize(&mut self) -> Result<usize> {
-        let mut data = [0u8; 8];
-        self.read_bytes(&mut data)?;
-        let s = unsafe { std::mem::transmute(data) };
-        Ok(s)
+        let s = self.read_u64()?;
+        usize::try_from(s).map_err(|_| Error::decoding("usize value too large for this platform"))
+    }
+
+    /// Write a length-prefixed slice of `u8`s to the channel.
+    #[inline]
+    fn write_vec(&mut self, bytes: &[u8]) -> Result<()> {
+        let len = (bytes.len() as u64).to_le_bytes();
+        self.write_bytes_vectored(&[&len, bytes])
+    }
+
+    /// Read a length-prefixed vector of `u8`s from the channel, accepting at
+    /// most `DEFAULT_MAX_VEC_LEN` bytes.
+    #[inline]
+    fn read_vec(&mut self) -> Result<Vec<u8>> {
+        self.read_vec_with_limit(DEFAULT_MAX_VEC_LEN)
+    }
+
+    /// Read a length-prefixed vector of `u8`s from the channel, failing with
+    /// `DecodingError` if the peer announces more than `max_len` bytes.
+    ///
+    /// The vector grows as its data arrives, rather than being allocated
+    /// up front with the announced length.
+    #[inline]
+    fn read_vec_with_limit(&mut self, max_len: usize) -> Result<Vec<u8>> {
+        let len = self.read_usize()?;
+        if len > max_len {
+            return Err(Error::decoding(format!(
+                "vector length {} exceeds maximum of {}",
+                len, max_len
+            )));
+        }
+        let mut data = Vec::with_capacity(std::cmp::min(len, VEC_CHUNK_LEN));
+        while data.len() < len {
+            let start = data.len();
+            let n = std::cmp::min(len - start, std::cmp::max(start, VEC_CHUNK_LEN));
+            data.resize(start + n, 0);
+            self.read_bytes(&mut data[start..])?;
+        }
+        Ok(data)
+    }
+
+    /// Write a length-prefixed UTF-8 string to the channel.
+    #[inline]
+    fn write_string(&mut self, s: &str) -> Result<()> {
+        self.write_vec(s.as_bytes())
+    }
+
+    /// Read a length-prefixed UTF-8 string from the channel, accepting at
+    /// most `DEFAULT_MAX_VEC_LEN` bytes.
+    #[inline]
+    fn read_string(&mut self) -> Result<String> {
+        let data = self.read_vec()?;
+        String::from_utf8(data).map_err(|_| Error::decoding("string is not valid UTF-8"))
     }
 
     /// Write a `Block` to the channel.
@@ -83,9 +293,7 @@ pub trait AbstractChannel {
     /// Write a `Block512` to the channel.
     #[inline(always)]
     fn write_block512(&mut self, b: &Block512) -> Result<()> {
-        for block in b.0.iter() {
-            self.write_block(block)?;
-        }
+        self.write_bytes(b.as_ref())?;
         Ok(())
     }
 
@@ -97,6 +305,61 @@ pub trait AbstractChannel {
         Ok(Block512::from(data))
     }
 
+    /// Write a slice of `Block`s to the channel with a single write.
+    #[inline]
+    fn write_blocks(&mut self, blocks: &[Block]) -> Result<()> {
+        let bytes =
+            unsafe { std::slice::from_raw_parts(blocks.as_ptr() as *const u8, blocks.len() * 16) };
+        self.write_bytes(bytes)
+    }
+
+    /// Read `blocks.len()` `Block`s from the channel with a single read.
+    #[inline]
+    fn read_blocks(&mut self, blocks: &mut [Block]) -> Result<()> {
+        let bytes = unsafe {
+            std::slice::from_raw_parts_mut(blocks.as_mut_ptr() as *mut u8, blocks.len() * 16)
+        };
+        self.read_bytes(bytes)
+    }
+
+    /// Write a slice of `Block512`s to the channel with a single write.
+    #[inline]
+    fn write_block512s(&mut self, blocks: &[Block512]) -> Result<()> {
+        let bytes =
+            unsafe { std::slice::from_raw_parts(blocks.as_ptr() as *const u8, blocks.len() * 64) };
+        self.write_bytes(bytes)
+    }
+
+    /// Read `blocks.len()` `Block512`s from the channel with a single read.
+    #[inline]
+    fn read_block512s(&mut self, blocks: &mut [Block512]) -> Result<()> {
+        let bytes = unsafe {
+            std::slice::from_raw_parts_mut(blocks.as_mut_ptr() as *mut u8, blocks.len() * 64)
+        };
+        self.read_bytes(bytes)
+    }
+
+    /// Write a slice of `bool`s to the channel, packed eight to a byte.
+    ///
+    /// Bit `i` is stored in bit `i % 8` of byte `i / 8`; unused bits of the
+    /// final byte are zero.
+    #[inline]
+    fn write_bits(&mut self, bits: &[bool]) -> Result<()> {
+        let mut data = vec![0u8; bits.len().div_ceil(8)];
+        for (i, bit) in bits.iter().enumerate() {
+            data[i / 8] |= (*bit as u8) << (i % 8);
+        }
+        self.write_bytes(&data)
+    }
+
+    /// Read `n` `bool`s written by `write_bits` from the channel.
+    #[inline]
+    fn read_bits(&mut self, n: usize) -> Result<Vec<bool>> {
+        let mut data = vec![0u8; n.div_ceil(8)];
+        self.read_bytes(&mut data)?;
+        Ok((0..n).map(|i| (data[i / 8] >> (i % 8)) & 1 == 1).collect())
+    }
+
     /// Write a `RistrettoPoint` to the channel.
     #[cfg(feature = "curve25519-dalek")]
     #[inline(always)]
@@ -111,23 +374,239 @@ pub trait AbstractChannel {
     fn read_pt(&mut self) -> Result<RistrettoPoint> {
         let mut data = [0u8; 32];
         self.read_bytes(&mut data)?;
-        let pt = match CompressedRistretto::from_slice(&data).decompress() {
-            Some(pt) => pt,
-            None => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "unable to decompress ristretto point",
-                ));
-            }
-        };
-        Ok(pt)
+        CompressedRistretto::from_slice(&data)
+            .decompress()
+            .ok_or_else(|| Error::decoding("unable to decompress ristretto point"))
+    }
+
+    /// Write a `Scalar` to the channel.
+    #[cfg(feature = "curve25519-dalek")]
+    #[inline(always)]
+    fn write_scalar(&mut self, s: &Scalar) -> Result<()> {
+        self.write_bytes(s.as_bytes())
+    }
+
+    /// Read a `Scalar` from the channel, failing with `DecodingError` if its
+    /// encoding is not canonical.
+    #[cfg(feature = "curve25519-dalek")]
+    #[inline(always)]
+    fn read_scalar(&mut self) -> Result<Scalar> {
+        let mut data = [0u8; 32];
+        self.read_bytes(&mut data)?;
+        let s = Scalar::from_bytes_mod_order(data);
+        if s.to_bytes() != data {
+            return Err(Error::decoding("non-canonical scalar"));
+        }
+        Ok(s)
+    }
+
+    /// Exchange wire-format versions with the other party.
+    ///
+    /// Both parties should call this immediately after connecting. Fails with
+    /// `ProtocolMisuse` if the peer is not speaking the scuttlebutt wire format
+    /// or is using a different `WIRE_FORMAT_VERSION`.
+    fn handshake(&mut self) -> Result<()> {
+        self.write_bytes(&WIRE_FORMAT_MAGIC)?;
+        self.write_u32(WIRE_FORMAT_VERSION)?;
+        self.flush()?;
+        let mut magic = [0u8; 4];
+        self.read_bytes(&mut magic)?;
+        if magic != WIRE_FORMAT_MAGIC {
+            return Err(Error::misuse(
+                "peer is not speaking the scuttlebutt wire format",
+            ));
+        }
+        let version = self.read_u32()?;
+        if version != WIRE_FORMAT_VERSION {
+            return Err(Error::misuse(format!(
+                "wire format version mismatch: ours is {}, peer's is {}",
+                WIRE_FORMAT_VERSION, version
+            )));
+        }
+        Ok(())
+    }
+
+    /// Tell the peer that this party is aborting the protocol, e.g., because
+    /// a check failed.
+    ///
+    /// Only channels that frame their data (`SessionChannel` and
+    /// `SecureChannel`) can signal an abort: they send an abort frame, and the
+    /// peer's next read past the data sent before it fails with `PeerAborted`.
+    /// A plain byte stream, such as `Channel`, `SyncChannel` or a wrapper
+    /// around them, cannot carry the abort alongside the data, so by default
+    /// this only flushes, and the peer's reads fail only once this party drops
+    /// its channel, or when they time out or are cancelled. Protocols whose
+    /// parties must learn of an abort should run over a framed channel, or set
+    /// timeouts on the underlying channel (see `Channel::set_read_timeout`).
+    fn abort(&mut self) -> Result<()> {
+        self.flush()
+    }
+
+    /// Report that data read from the channel failed a check, returning the
+    /// error to propagate, which by default is `error` itself.
+    ///
+    /// Protocols should pass every failed check on received data through this,
+    /// whether they then abort, return the error or carry on, so that a
+    /// `CostChannel`, whose reads are made up, can fail instead of measuring
+    /// a run that no honest peer would produce.
+    fn reject(&mut self, error: Error) -> Error {
+        error
+    }
+}
+
+/// Helpers for sending `serde`-serializable values over an `AbstractChannel`.
+///
+/// These are generic over the value type, so they live in a separate trait
+/// (implemented for every channel, including trait objects) to keep
+/// `AbstractChannel` object safe.
+#[cfg(feature = "serde")]
+pub trait AbstractChannelExt: AbstractChannel {
+    /// Write a `Serialize`-able value to the channel.
+    ///
+    /// The value is encoded with `bincode`'s compact (varint) encoding and
+    /// sent as a length-prefixed byte vector.
+    #[inline]
+    fn write_serde<T: Serialize>(&mut self, value: &T) -> Result<()> {
+        let data = bincode_options(DEFAULT_MAX_VEC_LEN)
+            .serialize(value)
+            .map_err(Error::misuse)?;
+        self.write_vec(&data)
+    }
+
+    /// Read a value written by `write_serde` from the channel, accepting an
+    /// encoding of at most `DEFAULT_MAX_VEC_LEN` bytes.
+    #[inline]
+    fn read_serde<T: DeserializeOwned>(&mut self) -> Result<T> {
+        self.read_serde_with_limit(DEFAULT_MAX_VEC_LEN)
+    }
+
+    /// Read a value written by `write_serde` from the channel, failing with
+    /// `DecodingError` if its encoding is longer than `max_len` bytes.
+    #[inline]
+    fn read_serde_with_limit<T: DeserializeOwned>(&mut self, max_len: usize) -> Result<T> {
+        let data = self.read_vec_with_limit(max_len)?;
+        bincode_options(max_len)
+            .deserialize(&data)
+            .map_err(Error::decoding)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<C: AbstractChannel + ?Sized> AbstractChannelExt for C {}
+
+impl<C: AbstractChannel + ?Sized> AbstractChannel for &mut C {
+    #[inline]
+    fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<()> {
+        (**self).read_bytes(bytes)
+    }
+
+    #[inline]
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        (**self).write_bytes(bytes)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+
+    #[inline]
+    fn write_bytes_vectored(&mut self, bufs: &[&[u8]]) -> Result<()> {
+        (**self).write_bytes_vectored(bufs)
+    }
+
+    #[cfg(feature = "curve25519-dalek")]
+    #[inline]
+    fn read_pt(&mut self) -> Result<RistrettoPoint> {
+        (**self).read_pt()
+    }
+
+    #[cfg(feature = "curve25519-dalek")]
+    #[inline]
+    fn read_scalar(&mut self) -> Result<Scalar> {
+        (**self).read_scalar()
+    }
+
+    #[inline]
+    fn handshake(&mut self) -> Result<()> {
+        (**self).handshake()
+    }
+
+    #[inline]
+    fn abort(&mut self) -> Result<()> {
+        (**self).abort()
+    }
+
+    #[inline]
+    fn reject(&mut self, error: Error) -> Error {
+        (**self).reject(error)
+    }
+}
+
+impl<C: AbstractChannel + ?Sized> AbstractChannel for Box<C> {
+    #[inline]
+    fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<()> {
+        (**self).read_bytes(bytes)
+    }
+
+    #[inline]
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        (**self).write_bytes(bytes)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+
+    #[inline]
+    fn write_bytes_vectored(&mut self, bufs: &[&[u8]]) -> Result<()> {
+        (**self).write_bytes_vectored(bufs)
+    }
+
+    #[cfg(feature = "curve25519-dalek")]
+    #[inline]
+    fn read_pt(&mut self) -> Result<RistrettoPoint> {
+        (**self).read_pt()
+    }
+
+    #[cfg(feature = "curve25519-dalek")]
+    #[inline]
+    fn read_scalar(&mut self) -> Result<Scalar> {
+        (**self).read_scalar()
+    }
+
+    #[inline]
+    fn handshake(&mut self) -> Result<()> {
+        (**self).handshake()
+    }
+
+    #[inline]
+    fn abort(&mut self) -> Result<()> {
+        (**self).abort()
+    }
+
+    #[inline]
+    fn reject(&mut self, error: Error) -> Error {
+        (**self).reject(error)
     }
 }
 
+/// The `bincode` configuration used by `write_serde` and `read_serde`.
+#[cfg(feature = "serde")]
+#[inline]
+fn bincode_options(max_len: usize) -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_little_endian()
+        .with_varint_encoding()
+        .with_limit(max_len as u64)
+}
+
 /// A standard read/write channel that implements `AbstractChannel`.
 pub struct Channel<R, W> {
     reader: Rc<RefCell<R>>,
     writer: Rc<RefCell<W>>,
+    control: Arc<Control>,
 }
 
 impl<R: Read, W: Write> Channel<R, W> {
@@ -135,7 +614,12 @@ impl<R: Read, W: Write> Channel<R, W> {
     pub fn new(reader: R, writer: W) -> Self {
         let reader = Rc::new(RefCell::new(reader));
         let writer = Rc::new(RefCell::new(writer));
-        Self { reader, writer }
+        let control = Arc::new(Control::default());
+        Self {
+            reader,
+            writer,
+            control,
+        }
     }
 
     /// Return a reader object wrapped in `Rc<RefCell>`.
@@ -147,30 +631,305 @@ impl<R: Read, W: Write> Channel<R, W> {
     pub fn writer(self) -> Rc<RefCell<W>> {
         self.writer.clone()
     }
+
+    /// Set the maximum time a single read may wait for data, or `None` to
+    /// wait forever (the default). A read that times out fails with
+    /// `TimedOut`.
+    ///
+    /// The timeout is only checked when the underlying reader returns, so it
+    /// should itself be configured to time out periodically (e.g., with
+    /// `TcpStream::set_read_timeout`).
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) {
+        self.control.set_read_timeout(timeout)
+    }
+
+    /// Return the read timeout set by `set_read_timeout`.
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.control.read_timeout()
+    }
+
+    /// Set the maximum time a single write or flush may wait for the writer,
+    /// or `None` to wait forever (the default). Like `set_read_timeout`, this
+    /// requires a writer that times out periodically.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) {
+        self.control.set_write_timeout(timeout)
+    }
+
+    /// Return the write timeout set by `set_write_timeout`.
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.control.write_timeout()
+    }
+
+    /// Return a handle that cancels the channel, which can be sent to another
+    /// thread.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.control.cancel_handle()
+    }
+
+    /// Split the channel into independent receive and send halves.
+    pub fn split(self) -> (ReadHalf<R>, WriteHalf<W>) {
+        (
+            ReadHalf::new(self.reader, self.control.clone()),
+            WriteHalf::new(self.writer, self.control),
+        )
+    }
+
+    /// Reunite the halves produced by `split` into a `Channel`.
+    ///
+    /// Fails with `ProtocolMisuse` if the halves come from different channels.
+    pub fn reunite(reader: ReadHalf<R>, writer: WriteHalf<W>) -> Result<Self> {
+        let (reader, control) = reader.into_inner();
+        let (writer, control_) = writer.into_inner();
+        check_halves(&control, &control_)?;
+        Ok(Self {
+            reader,
+            writer,
+            control,
+        })
+    }
 }
 
 impl<R: Read, W: Write> AbstractChannel for Channel<R, W> {
     #[inline(always)]
     fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
-        self.writer.borrow_mut().write_all(bytes)?;
-        Ok(())
+        self.control
+            .write_all(&mut *self.writer.borrow_mut(), bytes)
+    }
+
+    #[inline]
+    fn write_bytes_vectored(&mut self, bufs: &[&[u8]]) -> Result<()> {
+        self.control
+            .write_all_vectored(&mut *self.writer.borrow_mut(), bufs)
     }
 
     #[inline(always)]
-    fn read_bytes(&mut self, mut bytes: &mut [u8]) -> Result<()> {
-        self.reader.borrow_mut().read_exact(&mut bytes)
+    fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<()> {
+        self.control
+            .read_exact(&mut *self.reader.borrow_mut(), bytes)
     }
 
     #[inline(always)]
     fn flush(&mut self) -> Result<()> {
-        self.writer.borrow_mut().flush()
+        self.control.flush(&mut *self.writer.borrow_mut())
     }
+}
 
-    #[inline(always)]
+impl<R, W> Clone for Channel<R, W> {
     fn clone(&self) -> Self {
         Self {
             reader: self.reader.clone(),
             writer: self.writer.clone(),
+            control: self.control.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, BufWriter};
+    use std::os::unix::net::UnixStream;
+
+    type UnixChannel = Channel<BufReader<UnixStream>, BufWriter<UnixStream>>;
+
+    fn pair() -> (UnixChannel, UnixChannel) {
+        let (a, b) = UnixStream::pair().unwrap();
+        let a = Channel::new(BufReader::new(a.try_clone().unwrap()), BufWriter::new(a));
+        let b = Channel::new(BufReader::new(b.try_clone().unwrap()), BufWriter::new(b));
+        (a, b)
+    }
+
+    #[test]
+    fn test_integers() {
+        let (mut sender, mut receiver) = pair();
+        sender.write_u8(0xAB).unwrap();
+        sender.write_u16(0xBEEF).unwrap();
+        sender.write_u32(0xDEAD_BEEF).unwrap();
+        sender.write_u64(u64::MAX - 1).unwrap();
+        sender.write_u128(u128::MAX - 2).unwrap();
+        sender.write_i64(-42).unwrap();
+        sender.write_usize(12345).unwrap();
+        sender.flush().unwrap();
+        assert_eq!(receiver.read_u8().unwrap(), 0xAB);
+        assert_eq!(receiver.read_u16().unwrap(), 0xBEEF);
+        assert_eq!(receiver.read_u32().unwrap(), 0xDEAD_BEEF);
+        assert_eq!(receiver.read_u64().unwrap(), u64::MAX - 1);
+        assert_eq!(receiver.read_u128().unwrap(), u128::MAX - 2);
+        assert_eq!(receiver.read_i64().unwrap(), -42);
+        assert_eq!(receiver.read_usize().unwrap(), 12345);
+    }
+
+    #[test]
+    fn test_little_endian() {
+        let (mut sender, mut receiver) = pair();
+        sender.write_u32(0x0403_0201).unwrap();
+        sender.flush().unwrap();
+        let mut data = [0u8; 4];
+        receiver.read_bytes(&mut data).unwrap();
+        assert_eq!(data, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_vec_and_string() {
+        let (mut sender, mut receiver) = pair();
+        let v = (0..1000).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
+        sender.write_vec(&v).unwrap();
+        sender.write_string("hello world").unwrap();
+        sender.write_vec(&v).unwrap();
+        sender.flush().unwrap();
+        assert_eq!(receiver.read_vec().unwrap(), v);
+        assert_eq!(receiver.read_string().unwrap(), "hello world");
+        let err = receiver.read_vec_with_limit(999).unwrap_err();
+        assert!(matches!(err, Error::DecodingError(_)));
+    }
+
+    #[test]
+    fn test_long_vec() {
+        let (mut sender, mut receiver) = pair();
+        let v = vec![7u8; 3 * VEC_CHUNK_LEN + 1];
+        sender.write_vec(&v).unwrap();
+        // Announce the longest vector accepted, but send only a few bytes.
+        sender.write_usize(DEFAULT_MAX_VEC_LEN).unwrap();
+        sender.write_bytes(b"abc").unwrap();
+        sender.flush().unwrap();
+        drop(sender);
+        assert_eq!(receiver.read_vec().unwrap(), v);
+        let err = receiver.read_vec().unwrap_err();
+        assert!(matches!(err, Error::IoError(_)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Parameters {
+            name: String,
+            ninputs: u32,
+            wires: Vec<u64>,
+            delta: Block,
+        }
+
+        let (mut sender, mut receiver) = pair();
+        let params = Parameters {
+            name: "aes".to_string(),
+            ninputs: 256,
+            wires: vec![1, 2, 3, 1 << 40],
+            delta: rand::random::<Block>(),
+        };
+        sender.write_serde(&params).unwrap();
+        sender.write_serde(&params).unwrap();
+        sender.flush().unwrap();
+        assert_eq!(receiver.read_serde::<Parameters>().unwrap(), params);
+        let err = receiver.read_serde_with_limit::<Parameters>(8).unwrap_err();
+        assert!(matches!(err, Error::DecodingError(_)));
+    }
+
+    #[test]
+    fn test_blocks() {
+        let (mut sender, mut receiver) = pair();
+        let blocks = (0..100)
+            .map(|_| rand::random::<Block>())
+            .collect::<Vec<Block>>();
+        let blocks512 = (0..10)
+            .map(|_| rand::random::<Block512>())
+            .collect::<Vec<Block512>>();
+        sender.write_blocks(&blocks).unwrap();
+        sender.write_block512s(&blocks512).unwrap();
+        sender.flush().unwrap();
+        let mut blocks_ = vec![Block::default(); 100];
+        let mut blocks512_ = vec![Block512::default(); 10];
+        receiver.read_blocks(&mut blocks_).unwrap();
+        receiver.read_block512s(&mut blocks512_).unwrap();
+        assert_eq!(blocks, blocks_);
+        assert_eq!(blocks512, blocks512_);
+    }
+
+    #[test]
+    fn test_bits() {
+        let (mut sender, mut receiver) = pair();
+        let bits = (0..77)
+            .map(|_| rand::random::<bool>())
+            .collect::<Vec<bool>>();
+        sender.write_bits(&bits).unwrap();
+        sender.write_u8(0xFF).unwrap();
+        sender.flush().unwrap();
+        assert_eq!(receiver.read_bits(77).unwrap(), bits);
+        assert_eq!(receiver.read_u8().unwrap(), 0xFF);
+    }
+
+    #[test]
+    fn test_write_all_vectored() {
+        struct Trickle(Vec<u8>);
+        impl Write for Trickle {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                let n = std::cmp::min(3, buf.len());
+                self.0.extend_from_slice(&buf[..n]);
+                Ok(n)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut writer = Trickle(Vec::new());
+        Control::default()
+            .write_all_vectored(&mut writer, &[b"hello", b"", b" ", b"world"])
+            .unwrap();
+        assert_eq!(writer.0, b"hello world");
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let reader = BufReader::new(sender.try_clone().unwrap());
+            let writer = BufWriter::new(sender);
+            let mut channel = Channel::new(reader, writer);
+            channel.handshake().unwrap();
+        });
+        let reader = BufReader::new(receiver.try_clone().unwrap());
+        let writer = BufWriter::new(receiver);
+        let mut channel = Channel::new(reader, writer);
+        channel.handshake().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_handshake_mismatch() {
+        let (mut sender, mut receiver) = pair();
+        sender.write_bytes(&WIRE_FORMAT_MAGIC).unwrap();
+        sender.write_u32(WIRE_FORMAT_VERSION + 1).unwrap();
+        sender.flush().unwrap();
+        let err = receiver.handshake().unwrap_err();
+        assert!(matches!(err, Error::ProtocolMisuse(_)));
+    }
+
+    #[test]
+    fn test_trait_objects() {
+        let (a, b) = pair();
+        let x = rand::random::<Block>();
+        let mut sender: Box<dyn AbstractChannel> = Box::new(a);
+        let mut receiver = b;
+        let receiver: &mut dyn AbstractChannel = &mut receiver;
+        sender.write_block(&x).unwrap();
+        sender.write_string("hello").unwrap();
+        #[cfg(feature = "serde")]
+        sender.write_serde(&(1u8, 2u64)).unwrap();
+        sender.flush().unwrap();
+        assert_eq!(receiver.read_block().unwrap(), x);
+        assert_eq!(receiver.read_string().unwrap(), "hello");
+        #[cfg(feature = "serde")]
+        assert_eq!(receiver.read_serde::<(u8, u64)>().unwrap(), (1, 2));
+        // Trait objects can also be passed to code that is generic over a
+        // sized channel.
+        fn send_u64<C: AbstractChannel>(mut channel: C, x: u64) -> Result<()> {
+            channel.write_u64(x)?;
+            channel.flush()
         }
+        send_u64(&mut sender, 7).unwrap();
+        send_u64(&mut *sender, 8).unwrap();
+        assert_eq!(receiver.read_u64().unwrap(), 7);
+        assert_eq!(receiver.read_u64().unwrap(), 8);
     }
 }
@@ -0,0 +1,349 @@
+// -*- mode: rust; -*-
+//
+// This file is part of `scuttlebutt`.
+// Copyright © 2019 Galois, Inc.
+// See LICENSE for licensing information.
+
+use crate::{Error, Result};
+use std::io::{ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// How long to wait before retrying a transport that cannot make progress, at
+// first and at most; the wait doubles on each retry.
+const MIN_BACKOFF: Duration = Duration::from_micros(50);
+const MAX_BACKOFF: Duration = Duration::from_millis(10);
+// How many times a transport polling shared memory yields before it starts
+// backing off.
+#[cfg(feature = "shm")]
+const SPINS: usize = 64;
+
+/// A handle for cancelling a channel's pending and future operations, possibly
+/// from another thread.
+///
+/// Once cancelled, every read, write and flush on the channel (and its clones)
+/// fails with an `IoError` of kind `Other`.
+#[derive(Clone, Debug, Default)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    /// Cancel the channel.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Return whether the channel has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// The timeouts and cancellation state shared by all clones (and halves) of a
+/// `Channel` or `SyncChannel`, and held by a `ShmChannel`.
+///
+/// A deadline or cancellation can only be observed when the underlying reader
+/// or writer returns, so a transport that may block indefinitely should be
+/// configured to time out periodically (e.g., with
+/// `TcpStream::set_read_timeout`); the resulting `WouldBlock` and `TimedOut`
+/// errors are retried, with exponential backoff, until the channel's own
+/// deadline passes.
+///
+/// With the `tracing` feature, every read and flush emits a `TRACE` event
+/// (reads record how long they blocked), and timeouts and aborts emit `WARN`
+/// events.
+#[derive(Default)]
+pub(crate) struct Control {
+    read_timeout: Mutex<Option<Duration>>,
+    write_timeout: Mutex<Option<Duration>>,
+    cancel: CancelHandle,
+}
+
+/// The error returned when a channel reads an abort frame from the peer (see
+/// `AbstractChannel::abort`).
+#[inline]
+pub(crate) fn aborted() -> Error {
+    #[cfg(feature = "tracing")]
+    tracing::warn!("peer aborted the protocol");
+    Error::PeerAborted
+}
+
+#[inline]
+fn deadline(timeout: Option<Duration>) -> Option<Instant> {
+    timeout.map(|timeout| Instant::now() + timeout)
+}
+
+#[inline]
+fn would_block(e: &std::io::Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
+
+impl Control {
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) {
+        *self.read_timeout.lock().unwrap() = timeout;
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) {
+        *self.write_timeout.lock().unwrap() = timeout;
+    }
+
+    pub(crate) fn read_timeout(&self) -> Option<Duration> {
+        *self.read_timeout.lock().unwrap()
+    }
+
+    pub(crate) fn write_timeout(&self) -> Option<Duration> {
+        *self.write_timeout.lock().unwrap()
+    }
+
+    pub(crate) fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    #[inline]
+    pub(crate) fn check_cancelled(&self) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            Err(std::io::Error::other("channel operation cancelled").into())
+        } else {
+            Ok(())
+        }
+    }
+
+    // Called when the transport cannot make progress: fail if the channel was
+    // cancelled or `deadline` has passed, and otherwise wait for `backoff`
+    // (doubling it for next time) before letting the caller retry.
+    #[inline]
+    fn stalled(
+        &self,
+        deadline: Option<Instant>,
+        backoff: &mut Duration,
+        operation: &str,
+    ) -> Result<()> {
+        self.check_cancelled()?;
+        let mut wait = *backoff;
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(operation, "channel operation timed out");
+                return Err(std::io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("channel {} timed out", operation),
+                )
+                .into());
+            }
+            wait = std::cmp::min(wait, deadline - now);
+        }
+        std::thread::sleep(wait);
+        *backoff = std::cmp::min(*backoff * 2, MAX_BACKOFF);
+        Ok(())
+    }
+
+    /// Start waiting for a transport that is polled for progress, such as a
+    /// shared memory ring, giving up after `timeout`.
+    #[cfg(feature = "shm")]
+    pub(crate) fn poll(&self, timeout: Option<Duration>, operation: &'static str) -> Poll<'_> {
+        Poll {
+            control: self,
+            deadline: deadline(timeout),
+            backoff: MIN_BACKOFF,
+            spins: 0,
+            operation,
+        }
+    }
+
+    /// Fill `bytes` from `reader`.
+    pub(crate) fn read_exact<R: Read>(&self, reader: &mut R, mut bytes: &mut [u8]) -> Result<()> {
+        self.check_cancelled()?;
+        let deadline = deadline(self.read_timeout());
+        let mut backoff = MIN_BACKOFF;
+        #[cfg(feature = "tracing")]
+        let (start, nbytes) = (Instant::now(), bytes.len());
+        while !bytes.is_empty() {
+            match reader.read(bytes) {
+                Ok(0) => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    )
+                    .into());
+                }
+                Ok(n) => bytes = &mut std::mem::take(&mut bytes)[n..],
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(ref e) if would_block(e) => self.stalled(deadline, &mut backoff, "read")?,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        #[cfg(feature = "tracing")]
+        tracing::trace!(nbytes, elapsed = ?start.elapsed(), "channel read");
+        Ok(())
+    }
+
+    /// Write all of `bytes` to `writer`.
+    pub(crate) fn write_all<W: Write>(&self, writer: &mut W, mut bytes: &[u8]) -> Result<()> {
+        self.check_cancelled()?;
+        let deadline = deadline(self.write_timeout());
+        let mut backoff = MIN_BACKOFF;
+        while !bytes.is_empty() {
+            match writer.write(bytes) {
+                Ok(0) => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    )
+                    .into());
+                }
+                Ok(n) => bytes = &bytes[n..],
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(ref e) if would_block(e) => self.stalled(deadline, &mut backoff, "write")?,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Write all of `bufs` to `writer` using `Write::write_vectored`.
+    pub(crate) fn write_all_vectored<W: Write>(
+        &self,
+        writer: &mut W,
+        bufs: &[&[u8]],
+    ) -> Result<()> {
+        self.check_cancelled()?;
+        let deadline = deadline(self.write_timeout());
+        let mut backoff = MIN_BACKOFF;
+        let mut bufs = bufs.to_vec();
+        let mut start = 0;
+        while start < bufs.len() {
+            if bufs[start].is_empty() {
+                start += 1;
+                continue;
+            }
+            let slices = bufs[start..]
+                .iter()
+                .map(|buf| std::io::IoSlice::new(buf))
+                .collect::<Vec<std::io::IoSlice>>();
+            let mut n = match writer.write_vectored(&slices) {
+                Ok(0) => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    )
+                    .into());
+                }
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if would_block(e) => {
+                    self.stalled(deadline, &mut backoff, "write")?;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            while start < bufs.len() && n >= bufs[start].len() {
+                n -= bufs[start].len();
+                start += 1;
+            }
+            if n > 0 {
+                bufs[start] = &bufs[start][n..];
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush `writer`.
+    pub(crate) fn flush<W: Write>(&self, writer: &mut W) -> Result<()> {
+        self.check_cancelled()?;
+        let deadline = deadline(self.write_timeout());
+        let mut backoff = MIN_BACKOFF;
+        #[cfg(feature = "tracing")]
+        tracing::trace!("channel flush");
+        loop {
+            match writer.flush() {
+                Ok(()) => return Ok(()),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(ref e) if would_block(e) => self.stalled(deadline, &mut backoff, "flush")?,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// A wait on a polled transport (see `Control::poll`).
+#[cfg(feature = "shm")]
+pub(crate) struct Poll<'a> {
+    control: &'a Control,
+    deadline: Option<Instant>,
+    backoff: Duration,
+    spins: usize,
+    operation: &'static str,
+}
+
+#[cfg(feature = "shm")]
+impl Poll<'_> {
+    /// Wait before polling the transport again: yield the first few times, so
+    /// that a peer that is about to make progress is not slowed down, and then
+    /// back off until the deadline passes or the channel is cancelled.
+    #[inline]
+    pub(crate) fn wait(&mut self) -> Result<()> {
+        if self.spins < SPINS {
+            self.spins += 1;
+            self.control.check_cancelled()?;
+            std::thread::yield_now();
+            Ok(())
+        } else {
+            self.control
+                .stalled(self.deadline, &mut self.backoff, self.operation)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AbstractChannel, Block, Channel, Error, SyncChannel, TrackChannel};
+    use std::io::ErrorKind;
+    use std::os::unix::net::UnixStream;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_read_timeout() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        a.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let mut channel = SyncChannel::new(a.try_clone().unwrap(), a);
+        channel.set_read_timeout(Some(Duration::from_millis(50)));
+        let start = Instant::now();
+        let err = channel.read_block().unwrap_err();
+        assert!(matches!(err, Error::IoError(ref e) if e.kind() == ErrorKind::TimedOut));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        a.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let mut channel = Channel::new(a.try_clone().unwrap(), a);
+        let handle = channel.cancel_handle();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            handle.cancel();
+        });
+        // Half a block arrives; the read then blocks until it is cancelled.
+        std::io::Write::write_all(&mut b, &[0u8; 8]).unwrap();
+        let err = channel.read_block().unwrap_err();
+        assert!(matches!(err, Error::IoError(ref e) if e.kind() == ErrorKind::Other));
+        canceller.join().unwrap();
+        assert!(channel.write_block(&Block::default()).is_err());
+    }
+
+    #[test]
+    fn test_wrapped_timeout() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        a.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let channel = Channel::new(a.try_clone().unwrap(), a);
+        let mut tracked = TrackChannel::from_channel(channel.clone());
+        channel.set_read_timeout(Some(Duration::from_millis(20)));
+        let err = tracked.read_block().unwrap_err();
+        assert!(matches!(err, Error::IoError(ref e) if e.kind() == ErrorKind::TimedOut));
+        channel.cancel_handle().cancel();
+        assert!(tracked.write_block(&Block::default()).is_err());
+    }
+}
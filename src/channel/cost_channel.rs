@@ -0,0 +1,222 @@
+// -*- mode: rust; -*-
+//
+// This file is part of `scuttlebutt`.
+// Copyright © 2019 Galois, Inc.
+// See LICENSE for licensing information.
+
+use super::track_channel::Tracker;
+use super::{WIRE_FORMAT_MAGIC, WIRE_FORMAT_VERSION};
+use crate::{AbstractChannel, AesRng, Block, ChannelStats, Error, Result};
+#[cfg(feature = "curve25519-dalek")]
+use curve25519_dalek::ristretto::RistrettoPoint;
+#[cfg(feature = "curve25519-dalek")]
+use curve25519_dalek::scalar::Scalar;
+use rand::SeedableRng;
+use rand_core::RngCore;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+struct State {
+    rng: AesRng,
+    tracker: Tracker,
+    // Whether the protocol rejected data it read, or aborted.
+    rejected: bool,
+}
+
+#[inline]
+fn rejected() -> Error {
+    Error::misuse(
+        "dry run: the protocol rejected the simulated data it read, so its cost cannot be \
+         estimated with a `CostChannel`",
+    )
+}
+
+/// A channel for estimating the communication cost of one side of a protocol
+/// without running the other side.
+///
+/// Writes are counted and discarded, and reads are counted and filled with
+/// pseudo-random bytes from an `AesRng`, so the same seed gives the same run.
+/// The statistics are those of `TrackChannel` (with a zero `read_time`),
+/// including phases. Since the protocol never sees what the other party would
+/// have sent, the counts are an upper bound only for protocols whose
+/// communication pattern does not depend on the values received.
+///
+/// A protocol that checks the data it reads will usually find it invalid.
+/// Points and scalars read with `read_pt` and `read_scalar` are always valid,
+/// and `handshake` accepts any peer, but once the protocol reports a failed
+/// check with `reject` (or calls `abort`), `reject` returns `ProtocolMisuse`
+/// with a message saying the dry run is not meaningful, and so does every
+/// subsequent operation, as well as `stats` and `phases`. This holds whether
+/// the protocol then fails or takes another branch, as long as it reports its
+/// checks through `reject`, as the protocols in this crate do. All clones of a
+/// `CostChannel` share the same state.
+pub struct CostChannel {
+    state: Rc<RefCell<State>>,
+}
+
+impl CostChannel {
+    /// Make a new `CostChannel` whose reads are generated from `seed`.
+    pub fn new(seed: Block) -> Self {
+        let state = State {
+            rng: AesRng::from_seed(seed),
+            tracker: Tracker::new(),
+            rejected: false,
+        };
+        Self {
+            state: Rc::new(RefCell::new(state)),
+        }
+    }
+
+    /// Attribute all subsequent communication to the phase `name`.
+    pub fn begin_phase(&mut self, name: &str) {
+        self.state.borrow_mut().tracker.begin_phase(name);
+    }
+
+    /// Return the statistics of each phase, in the order the phases began.
+    pub fn phases(&self) -> Result<Vec<(String, ChannelStats)>> {
+        let state = self.state.borrow();
+        if state.rejected {
+            return Err(rejected());
+        }
+        Ok(state.tracker.phases.clone())
+    }
+
+    /// Return the statistics summed over all phases.
+    pub fn stats(&self) -> Result<ChannelStats> {
+        let state = self.state.borrow();
+        if state.rejected {
+            return Err(rejected());
+        }
+        Ok(state.tracker.total())
+    }
+
+    #[inline]
+    fn check(&self) -> Result<()> {
+        if self.state.borrow().rejected {
+            Err(rejected())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl AbstractChannel for CostChannel {
+    #[inline]
+    fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<()> {
+        self.check()?;
+        let mut state = self.state.borrow_mut();
+        state.rng.fill_bytes(bytes);
+        state.tracker.read(bytes.len(), Duration::default());
+        Ok(())
+    }
+
+    #[inline]
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.check()?;
+        self.state.borrow_mut().tracker.wrote(bytes.len());
+        Ok(())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<()> {
+        self.check()?;
+        self.state.borrow_mut().tracker.flushed();
+        Ok(())
+    }
+
+    #[cfg(feature = "curve25519-dalek")]
+    #[inline]
+    fn read_pt(&mut self) -> Result<RistrettoPoint> {
+        self.read_bytes(&mut [0u8; 32])?;
+        let mut data = [0u8; 64];
+        self.state.borrow_mut().rng.fill_bytes(&mut data);
+        Ok(RistrettoPoint::from_uniform_bytes(&data))
+    }
+
+    #[cfg(feature = "curve25519-dalek")]
+    #[inline]
+    fn read_scalar(&mut self) -> Result<Scalar> {
+        self.read_bytes(&mut [0u8; 32])?;
+        let mut data = [0u8; 64];
+        self.state.borrow_mut().rng.fill_bytes(&mut data);
+        Ok(Scalar::from_bytes_mod_order_wide(&data))
+    }
+
+    fn handshake(&mut self) -> Result<()> {
+        self.write_bytes(&WIRE_FORMAT_MAGIC)?;
+        self.write_u32(WIRE_FORMAT_VERSION)?;
+        self.flush()?;
+        self.read_bytes(&mut [0u8; 8])
+    }
+
+    fn abort(&mut self) -> Result<()> {
+        self.state.borrow_mut().rejected = true;
+        Err(rejected())
+    }
+
+    fn reject(&mut self, _error: Error) -> Error {
+        self.state.borrow_mut().rejected = true;
+        rejected()
+    }
+}
+
+impl Clone for CostChannel {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cointoss;
+
+    #[test]
+    fn test_cost() {
+        let mut channel = CostChannel::new(Block::default());
+        channel.handshake().unwrap();
+        channel.begin_phase("cointoss");
+        let seeds = [rand::random::<Block>(); 2];
+        let _ = cointoss::send(&mut channel.clone(), &seeds).unwrap();
+        let phases = channel.phases().unwrap();
+        assert_eq!(phases.len(), 2);
+        assert_eq!(phases[0].1.bytes_written, 8);
+        assert_eq!(phases[0].1.bytes_read, 8);
+        let stats = &phases[1].1;
+        assert_eq!(stats.bytes_written, 64);
+        assert_eq!(stats.bytes_read, 32);
+        assert_eq!(stats.messages_sent, 2);
+        assert_eq!(stats.rounds, 3);
+        // The same seed gives the same reads.
+        let mut a = CostChannel::new(Block::default());
+        let mut b = CostChannel::new(Block::default());
+        assert_eq!(a.read_block().unwrap(), b.read_block().unwrap());
+    }
+
+    #[test]
+    fn test_rejected() {
+        let mut channel = CostChannel::new(Block::default());
+        let channel_: &mut dyn AbstractChannel = &mut channel;
+        // The receiver's commitment check fails on random data.
+        match cointoss::receive(channel_, &[rand::random::<Block>()]) {
+            Err(Error::ProtocolMisuse(_)) => (),
+            _ => panic!("commitment check passed on random data"),
+        }
+        assert!(matches!(channel.stats(), Err(Error::ProtocolMisuse(_))));
+        assert!(channel.write_block(&Block::default()).is_err());
+    }
+
+    #[test]
+    fn test_rejected_branch() {
+        // A protocol that carries on after a failed check, without aborting.
+        let mut channel = CostChannel::new(Block::default());
+        if channel.read_u64().unwrap() != 42 {
+            let err = channel.reject(Error::verification("unexpected value"));
+            assert!(matches!(err, Error::ProtocolMisuse(_)));
+        }
+        assert!(matches!(channel.stats(), Err(Error::ProtocolMisuse(_))));
+    }
+}
@@ -0,0 +1,286 @@
+// -*- mode: rust; -*-
+//
+// This file is part of `scuttlebutt`.
+// Copyright © 2019 Galois, Inc.
+// See LICENSE for licensing information.
+
+use crate::{AbstractChannel, Channel, Result};
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+/// A fault that a `FaultChannel` can inject into an outgoing message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Flip bit `i` of the message, i.e., bit `i % 8` of byte `i / 8`.
+    FlipBit(usize),
+    /// Truncate the message to its first `n` bytes.
+    Truncate(usize),
+    /// Drop the message entirely.
+    Drop,
+    /// Send the message after the next one (or at the next flush, if that
+    /// comes first).
+    Reorder,
+    /// Replace the message with the given bytes.
+    Replace(Vec<u8>),
+}
+
+type Predicate = Box<dyn Fn(usize, &[u8]) -> bool>;
+
+enum Selector {
+    Index(usize),
+    Predicate(Predicate),
+}
+
+impl Selector {
+    #[inline]
+    fn matches(&self, index: usize, message: &[u8]) -> bool {
+        match self {
+            Selector::Index(i) => *i == index,
+            Selector::Predicate(f) => f(index, message),
+        }
+    }
+}
+
+struct Faults {
+    rules: Vec<(Selector, Fault)>,
+    // The index of the next message to be written.
+    index: usize,
+    // A message held back by `Fault::Reorder`.
+    held: Option<Vec<u8>>,
+    // The number of faults injected so far.
+    injected: usize,
+}
+
+/// A channel that tampers with outgoing messages, for testing that protocols
+/// detect an actively malicious peer.
+///
+/// Each call to `write_bytes` counts as one message, indexed from zero, as does
+/// each vectored write, so that a `write_vec` is a single message made of the
+/// length prefix and the data.
+/// Messages are selected either by index (`inject_at`) or by a predicate on the
+/// index and contents (`inject_when`); the first matching rule determines the
+/// fault applied. Reads are passed through untouched.
+pub struct FaultChannel<R, W> {
+    channel: Channel<R, W>,
+    faults: Rc<RefCell<Faults>>,
+}
+
+impl<R: Read, W: Write> FaultChannel<R, W> {
+    /// Make a new `FaultChannel` from a `reader` and a `writer`.
+    pub fn new(reader: R, writer: W) -> Self {
+        Self::from_channel(Channel::new(reader, writer))
+    }
+
+    /// Make a new `FaultChannel` tampering with the messages written to
+    /// `channel`.
+    ///
+    /// Timeouts and cancellation are controlled through `channel`, or a clone
+    /// of it kept by the caller.
+    pub fn from_channel(channel: Channel<R, W>) -> Self {
+        let faults = Faults {
+            rules: Vec::new(),
+            index: 0,
+            held: None,
+            injected: 0,
+        };
+        Self {
+            channel,
+            faults: Rc::new(RefCell::new(faults)),
+        }
+    }
+
+    /// Inject `fault` into the `index`th message written.
+    pub fn inject_at(&mut self, index: usize, fault: Fault) {
+        self.faults
+            .borrow_mut()
+            .rules
+            .push((Selector::Index(index), fault));
+    }
+
+    /// Inject `fault` into every message for which `predicate(index, message)`
+    /// returns `true`.
+    pub fn inject_when<F: Fn(usize, &[u8]) -> bool + 'static>(
+        &mut self,
+        predicate: F,
+        fault: Fault,
+    ) {
+        self.faults
+            .borrow_mut()
+            .rules
+            .push((Selector::Predicate(Box::new(predicate)), fault));
+    }
+
+    /// Return the number of faults injected so far.
+    pub fn injected(&self) -> usize {
+        self.faults.borrow().injected
+    }
+}
+
+impl<R: Read, W: Write> AbstractChannel for FaultChannel<R, W> {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        let mut faults = self.faults.borrow_mut();
+        let index = faults.index;
+        faults.index += 1;
+        let fault = faults
+            .rules
+            .iter()
+            .find(|(selector, _)| selector.matches(index, bytes))
+            .map(|(_, fault)| fault.clone());
+        let held = faults.held.take();
+        if fault.is_some() {
+            faults.injected += 1;
+        }
+        match fault {
+            None => self.channel.write_bytes(bytes)?,
+            Some(Fault::FlipBit(i)) => {
+                let mut bytes = bytes.to_vec();
+                if i / 8 < bytes.len() {
+                    bytes[i / 8] ^= 1 << (i % 8);
+                }
+                self.channel.write_bytes(&bytes)?;
+            }
+            Some(Fault::Truncate(n)) => {
+                let n = std::cmp::min(n, bytes.len());
+                self.channel.write_bytes(&bytes[..n])?;
+            }
+            Some(Fault::Drop) => (),
+            Some(Fault::Reorder) => faults.held = Some(bytes.to_vec()),
+            Some(Fault::Replace(replacement)) => self.channel.write_bytes(&replacement)?,
+        }
+        if let Some(held) = held {
+            self.channel.write_bytes(&held)?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn write_bytes_vectored(&mut self, bufs: &[&[u8]]) -> Result<()> {
+        self.write_bytes(&bufs.concat())
+    }
+
+    #[inline]
+    fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<()> {
+        self.channel.read_bytes(bytes)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<()> {
+        if let Some(held) = self.faults.borrow_mut().held.take() {
+            self.channel.write_bytes(&held)?;
+        }
+        self.channel.flush()
+    }
+}
+
+impl<R, W> Clone for FaultChannel<R, W> {
+    fn clone(&self) -> Self {
+        Self {
+            channel: self.channel.clone(),
+            faults: self.faults.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cointoss, Block, Error};
+    use std::io::{BufReader, BufWriter};
+    use std::os::unix::net::UnixStream;
+
+    // Run the coin tossing protocol with a sender that injects `fault` into the
+    // `index`th message, returning the receiver's result.
+    fn cointoss_with_fault(nseeds: usize, index: usize, fault: Fault) -> Result<Vec<Block>> {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let seeds = (0..nseeds)
+            .map(|_| rand::random::<Block>())
+            .collect::<Vec<Block>>();
+        let seeds_ = seeds.clone();
+        let handle = std::thread::spawn(move || {
+            let reader = BufReader::new(sender.try_clone().unwrap());
+            let writer = BufWriter::new(sender);
+            let mut channel = FaultChannel::new(reader, writer);
+            channel.inject_at(index, fault);
+            let _ = cointoss::send(&mut channel, &seeds_);
+            assert_eq!(channel.injected(), 1);
+        });
+        let reader = BufReader::new(receiver.try_clone().unwrap());
+        let writer = BufWriter::new(receiver);
+        let mut channel = Channel::new(reader, writer);
+        let result = cointoss::receive(&mut channel, &seeds);
+        handle.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn test_flip_bit() {
+        match cointoss_with_fault(1, 1, Fault::FlipBit(3)) {
+            Err(Error::VerificationFailed(_)) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_replace() {
+        match cointoss_with_fault(1, 0, Fault::Replace(vec![0u8; 16])) {
+            Err(Error::VerificationFailed(_)) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_reorder() {
+        match cointoss_with_fault(2, 2, Fault::Reorder) {
+            Err(Error::VerificationFailed(_)) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_truncate_and_drop() {
+        match cointoss_with_fault(1, 1, Fault::Truncate(8)) {
+            Err(Error::IoError(_)) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match cointoss_with_fault(1, 1, Fault::Drop) {
+            Err(Error::IoError(_)) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_predicate() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut sender = FaultChannel::new(a.try_clone().unwrap(), a);
+        let mut receiver = Channel::new(b.try_clone().unwrap(), b);
+        sender.inject_when(
+            |_, message| message == b"secret",
+            Fault::Replace(b"public".to_vec()),
+        );
+        sender.write_bytes(b"hello").unwrap();
+        sender.write_bytes(b"secret").unwrap();
+        sender.flush().unwrap();
+        let mut data = [0u8; 11];
+        receiver.read_bytes(&mut data).unwrap();
+        assert_eq!(&data, b"hellopublic");
+        assert_eq!(sender.injected(), 1);
+    }
+
+    #[test]
+    fn test_vec() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut sender = FaultChannel::new(a.try_clone().unwrap(), a);
+        let mut receiver = Channel::new(b.try_clone().unwrap(), b);
+        // Flip the lowest bit of the first byte after the length prefix.
+        sender.inject_at(1, Fault::FlipBit(64));
+        sender.write_u8(1).unwrap();
+        sender.write_vec(b"hello").unwrap();
+        sender.write_u8(2).unwrap();
+        sender.flush().unwrap();
+        assert_eq!(receiver.read_u8().unwrap(), 1);
+        assert_eq!(receiver.read_vec().unwrap(), b"iello");
+        assert_eq!(receiver.read_u8().unwrap(), 2);
+        assert_eq!(sender.injected(), 1);
+    }
+}
@@ -4,56 +4,180 @@
 // Copyright © 2019 Galois, Inc.
 // See LICENSE for licensing information.
 
-use crate::{AbstractChannel, Channel};
+use crate::{AbstractChannel, Channel, Result};
 use sha2::{Digest, Sha256};
-use std::io::{Read, Result, Write};
+use std::io::{Read, Write};
 
 /// An instantiation of the `AbstractChannel` trait which computes a running
 /// hash of all bytes read from and written to the channel.
+///
+/// Bytes written and bytes read are hashed separately, and the two hashes are
+/// combined with the initiator's hash first. Hence, once both parties have
+/// read everything the other has written, they compute the same transcript
+/// digest, and the digest changes if the parties swap roles.
 pub struct HashChannel<R, W> {
     channel: Channel<R, W>,
-    hash: Sha256,
+    initiator: bool,
+    sent: Sha256,
+    received: Sha256,
 }
 
 impl<R: Read, W: Write> HashChannel<R, W> {
     /// Make a new `HashChannel` from a `reader` and a `writer`.
-    pub fn new(reader: R, writer: W) -> Self {
-        let channel = Channel::new(reader, writer);
-        let hash = Sha256::new();
-        Self { channel, hash }
+    ///
+    /// Exactly one of the two parties must set `initiator`.
+    pub fn new(reader: R, writer: W, initiator: bool) -> Self {
+        Self::from_channel(Channel::new(reader, writer), initiator)
     }
 
-    /// Consume the channel and output the hash of all the communication.
-    pub fn finish(self) -> [u8; 32] {
+    /// Make a new `HashChannel` hashing the communication over `channel`.
+    ///
+    /// Timeouts and cancellation are controlled through `channel`, or a clone
+    /// of it kept by the caller.
+    pub fn from_channel(channel: Channel<R, W>, initiator: bool) -> Self {
+        Self {
+            channel,
+            initiator,
+            sent: Sha256::new(),
+            received: Sha256::new(),
+        }
+    }
+
+    /// Output the hash of all the communication so far, without consuming the
+    /// channel.
+    ///
+    /// This can be used to derive Fiat–Shamir challenges mid-protocol, as long
+    /// as it is called at a point where both parties have seen the same
+    /// messages (e.g., right after a round has been fully read).
+    pub fn digest(&self) -> [u8; 32] {
+        let sent = self.sent.clone().result();
+        let received = self.received.clone().result();
+        let (first, second) = if self.initiator {
+            (sent, received)
+        } else {
+            (received, sent)
+        };
+        let mut hash = Sha256::new();
+        hash.input(b"scuttlebutt transcript");
+        hash.input(first);
+        hash.input(second);
         let mut h = [0u8; 32];
-        h.copy_from_slice(&self.hash.result());
+        h.copy_from_slice(&hash.result());
         h
     }
+
+    /// Consume the channel and output the hash of all the communication.
+    pub fn finish(self) -> [u8; 32] {
+        self.digest()
+    }
 }
 
 impl<R: Read, W: Write> AbstractChannel for HashChannel<R, W> {
     #[inline]
     fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
-        self.hash.input(bytes);
+        self.sent.input(bytes);
         self.channel.write_bytes(bytes)
     }
 
     #[inline]
-    fn read_bytes(&mut self, mut bytes: &mut [u8]) -> Result<()> {
-        self.hash.input(&bytes);
-        self.channel.read_bytes(&mut bytes)
+    fn write_bytes_vectored(&mut self, bufs: &[&[u8]]) -> Result<()> {
+        for buf in bufs.iter() {
+            self.sent.input(buf);
+        }
+        self.channel.write_bytes_vectored(bufs)
+    }
+
+    #[inline]
+    fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<()> {
+        self.channel.read_bytes(bytes)?;
+        self.received.input(&bytes);
+        Ok(())
     }
 
     #[inline]
     fn flush(&mut self) -> Result<()> {
         self.channel.flush()
     }
+}
 
-    #[inline]
+impl<R, W> Clone for HashChannel<R, W> {
     fn clone(&self) -> Self {
         Self {
             channel: self.channel.clone(),
-            hash: self.hash.clone(),
+            initiator: self.initiator,
+            sent: self.sent.clone(),
+            received: self.received.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Block;
+    use std::io::{BufReader, BufWriter};
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_digests_agree() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let x = rand::random::<Block>();
+        let y = rand::random::<Block>();
+        let handle = std::thread::spawn(move || {
+            let reader = BufReader::new(sender.try_clone().unwrap());
+            let writer = BufWriter::new(sender);
+            let mut channel = HashChannel::new(reader, writer, true);
+            channel.write_block(&x).unwrap();
+            channel.flush().unwrap();
+            let y_ = channel.read_block().unwrap();
+            assert_eq!(y, y_);
+            let checkpoint = channel.digest();
+            channel.write_bytes(&[1, 2, 3]).unwrap();
+            channel.flush().unwrap();
+            (checkpoint, channel.finish())
+        });
+        let reader = BufReader::new(receiver.try_clone().unwrap());
+        let writer = BufWriter::new(receiver);
+        let mut channel = HashChannel::new(reader, writer, false);
+        let x_ = channel.read_block().unwrap();
+        assert_eq!(x, x_);
+        channel.write_block(&y).unwrap();
+        channel.flush().unwrap();
+        let checkpoint = channel.digest();
+        let mut data = [0u8; 3];
+        channel.read_bytes(&mut data).unwrap();
+        let digest = channel.finish();
+        let (checkpoint_, digest_) = handle.join().unwrap();
+        assert_eq!(checkpoint, checkpoint_);
+        assert_eq!(digest, digest_);
+        assert_ne!(checkpoint, digest);
+    }
+
+    #[test]
+    fn test_received_bytes_are_hashed() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut sender = HashChannel::new(BufReader::new(a.try_clone().unwrap()), a, true);
+        let mut receiver = HashChannel::new(BufReader::new(b.try_clone().unwrap()), b, false);
+        let empty = receiver.digest();
+        sender.write_bytes(&[0u8; 16]).unwrap();
+        let mut data = [0xFFu8; 16];
+        receiver.read_bytes(&mut data).unwrap();
+        assert_ne!(receiver.digest(), empty);
+        assert_eq!(receiver.digest(), sender.digest());
+    }
+
+    #[test]
+    fn test_roles_are_bound() {
+        let digest = |initiator| {
+            let (a, b) = UnixStream::pair().unwrap();
+            let mut sender = HashChannel::new(BufReader::new(a.try_clone().unwrap()), a, initiator);
+            let mut receiver =
+                HashChannel::new(BufReader::new(b.try_clone().unwrap()), b, !initiator);
+            sender.write_bytes(&[1, 2, 3]).unwrap();
+            receiver.read_bytes(&mut [0u8; 3]).unwrap();
+            assert_eq!(sender.digest(), receiver.digest());
+            sender.digest()
+        };
+        assert_ne!(digest(true), digest(false));
+    }
+}
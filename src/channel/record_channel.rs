@@ -0,0 +1,304 @@
+// -*- mode: rust; -*-
+//
+// This file is part of `scuttlebutt`.
+// Copyright © 2019 Galois, Inc.
+// See LICENSE for licensing information.
+
+use crate::{AbstractChannel, Channel, Error, Result};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const TRANSCRIPT_MAGIC: [u8; 8] = *b"SCBTREC1";
+
+/// The direction of a recorded transcript entry, from the point of view of the
+/// recording party.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TranscriptDirection {
+    /// Bytes read from the other party.
+    Read,
+    /// Bytes written to the other party.
+    Write,
+}
+
+/// A single read or write recorded by a `RecordChannel`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TranscriptEntry {
+    /// Whether the bytes were read or written.
+    pub direction: TranscriptDirection,
+    /// The time since the channel was created.
+    pub timestamp: Duration,
+    /// The bytes read or written.
+    pub bytes: Vec<u8>,
+}
+
+/// Read all the entries of a transcript written by a `RecordChannel`.
+pub fn read_transcript<P: AsRef<Path>>(path: P) -> Result<Vec<TranscriptEntry>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic)?;
+    if magic != TRANSCRIPT_MAGIC {
+        return Err(Error::decoding("not a scuttlebutt transcript"));
+    }
+    let mut entries = Vec::new();
+    loop {
+        let mut direction = [0u8; 1];
+        match file.read_exact(&mut direction) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let direction = match direction[0] {
+            0 => TranscriptDirection::Read,
+            1 => TranscriptDirection::Write,
+            _ => {
+                return Err(Error::decoding("invalid direction in transcript"));
+            }
+        };
+        let mut data = [0u8; 8];
+        file.read_exact(&mut data)?;
+        let timestamp = Duration::from_micros(u64::from_le_bytes(data));
+        file.read_exact(&mut data)?;
+        let len = u64::from_le_bytes(data);
+        // Read through `take` so that the entry grows with the data actually
+        // in the file, whatever its stated length.
+        let mut bytes = Vec::new();
+        (&mut file).take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        entries.push(TranscriptEntry {
+            direction,
+            timestamp,
+            bytes,
+        });
+    }
+    Ok(entries)
+}
+
+/// A channel that records every read and write to a transcript file.
+///
+/// Each entry stores the direction, the time since the channel was created,
+/// and the bytes themselves. The transcript can be loaded with
+/// `read_transcript`, or played back with `ReplayChannel`.
+pub struct RecordChannel<R, W> {
+    channel: Channel<R, W>,
+    log: Rc<RefCell<BufWriter<File>>>,
+    start: Instant,
+}
+
+impl<R: Read, W: Write> RecordChannel<R, W> {
+    /// Make a new `RecordChannel` from a `reader` and a `writer`, recording
+    /// the transcript to the file at `path`.
+    pub fn new<P: AsRef<Path>>(reader: R, writer: W, path: P) -> Result<Self> {
+        Self::from_channel(Channel::new(reader, writer), path)
+    }
+
+    /// Make a new `RecordChannel` recording the communication over `channel`
+    /// to the file at `path`.
+    ///
+    /// Timeouts and cancellation are controlled through `channel`, or a clone
+    /// of it kept by the caller.
+    pub fn from_channel<P: AsRef<Path>>(channel: Channel<R, W>, path: P) -> Result<Self> {
+        let mut log = BufWriter::new(File::create(path)?);
+        log.write_all(&TRANSCRIPT_MAGIC)?;
+        let log = Rc::new(RefCell::new(log));
+        Ok(Self {
+            channel,
+            log,
+            start: Instant::now(),
+        })
+    }
+
+    #[inline]
+    fn record(&self, direction: TranscriptDirection, bytes: &[u8]) -> Result<()> {
+        let timestamp = self.start.elapsed().as_micros() as u64;
+        let mut log = self.log.borrow_mut();
+        log.write_all(&[direction as u8])?;
+        log.write_all(&timestamp.to_le_bytes())?;
+        log.write_all(&(bytes.len() as u64).to_le_bytes())?;
+        log.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+impl<R: Read, W: Write> AbstractChannel for RecordChannel<R, W> {
+    #[inline]
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.channel.write_bytes(bytes)?;
+        self.record(TranscriptDirection::Write, bytes)
+    }
+
+    #[inline]
+    fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<()> {
+        self.channel.read_bytes(bytes)?;
+        self.record(TranscriptDirection::Read, bytes)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<()> {
+        self.channel.flush()?;
+        self.log.borrow_mut().flush()?;
+        Ok(())
+    }
+}
+
+impl<R, W> Clone for RecordChannel<R, W> {
+    fn clone(&self) -> Self {
+        Self {
+            channel: self.channel.clone(),
+            log: self.log.clone(),
+            start: self.start,
+        }
+    }
+}
+
+struct Replay {
+    incoming: Vec<u8>,
+    read_pos: usize,
+    outgoing: Vec<u8>,
+    write_pos: usize,
+}
+
+/// A channel that plays back the peer of a party recorded by a
+/// `RecordChannel`.
+///
+/// Reads are served from the bytes the recorded party read, and writes are
+/// checked against the bytes the recorded party wrote. Running the recorded
+/// party again with the same inputs and randomness thus reproduces the
+/// original run without the other party; any divergence results in a
+/// `ProtocolMisuse` error.
+pub struct ReplayChannel {
+    replay: Rc<RefCell<Replay>>,
+}
+
+impl ReplayChannel {
+    /// Make a new `ReplayChannel` from the entries of a transcript.
+    pub fn new(entries: &[TranscriptEntry]) -> Self {
+        let mut incoming = Vec::new();
+        let mut outgoing = Vec::new();
+        for entry in entries.iter() {
+            match entry.direction {
+                TranscriptDirection::Read => incoming.extend_from_slice(&entry.bytes),
+                TranscriptDirection::Write => outgoing.extend_from_slice(&entry.bytes),
+            }
+        }
+        let replay = Replay {
+            incoming,
+            read_pos: 0,
+            outgoing,
+            write_pos: 0,
+        };
+        Self {
+            replay: Rc::new(RefCell::new(replay)),
+        }
+    }
+
+    /// Make a new `ReplayChannel` from the transcript file at `path`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let entries = read_transcript(path)?;
+        Ok(Self::new(&entries))
+    }
+
+    /// Return `true` if every recorded byte has been read and written.
+    pub fn is_finished(&self) -> bool {
+        let replay = self.replay.borrow();
+        replay.read_pos == replay.incoming.len() && replay.write_pos == replay.outgoing.len()
+    }
+}
+
+impl AbstractChannel for ReplayChannel {
+    #[inline]
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        let mut replay = self.replay.borrow_mut();
+        let start = replay.write_pos;
+        let end = start + bytes.len();
+        if end > replay.outgoing.len() || replay.outgoing[start..end] != *bytes {
+            return Err(Error::misuse(format!(
+                "write diverges from recorded transcript at byte {}",
+                start
+            )));
+        }
+        replay.write_pos = end;
+        Ok(())
+    }
+
+    #[inline]
+    fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<()> {
+        let mut replay = self.replay.borrow_mut();
+        let start = replay.read_pos;
+        let end = start + bytes.len();
+        if end > replay.incoming.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "read past the end of the recorded transcript",
+            )
+            .into());
+        }
+        bytes.copy_from_slice(&replay.incoming[start..end]);
+        replay.read_pos = end;
+        Ok(())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Clone for ReplayChannel {
+    fn clone(&self) -> Self {
+        Self {
+            replay: self.replay.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cointoss, Block};
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!(
+            "scuttlebutt-transcript-{}-{}",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let seed = rand::random::<Block>();
+        let seed_ = rand::random::<Block>();
+        let handle = std::thread::spawn(move || {
+            let reader = BufReader::new(sender.try_clone().unwrap());
+            let writer = BufWriter::new(sender);
+            let mut channel = Channel::new(reader, writer);
+            cointoss::send(&mut channel, &[seed]).unwrap();
+        });
+        let reader = BufReader::new(receiver.try_clone().unwrap());
+        let writer = BufWriter::new(receiver);
+        let mut channel = RecordChannel::new(reader, writer, &path).unwrap();
+        let output = cointoss::receive(&mut channel, &[seed_]).unwrap();
+        channel.flush().unwrap();
+        handle.join().unwrap();
+
+        let entries = read_transcript(&path).unwrap();
+        assert_eq!(entries[0].direction, TranscriptDirection::Read);
+        assert_eq!(entries[1].direction, TranscriptDirection::Write);
+        assert_eq!(entries[1].bytes, seed_.as_ref());
+
+        let mut channel = ReplayChannel::from_file(&path).unwrap();
+        let output_ = cointoss::receive(&mut channel, &[seed_]).unwrap();
+        assert_eq!(output, output_);
+        assert!(channel.is_finished());
+
+        let mut channel = ReplayChannel::new(&entries);
+        let seed__ = rand::random::<Block>();
+        assert!(cointoss::receive(&mut channel, &[seed__]).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,518 @@
+// -*- mode: rust; -*-
+//
+// This file is part of `scuttlebutt`.
+// Copyright © 2019 Galois, Inc.
+// See LICENSE for licensing information.
+
+use super::aborted;
+use crate::{utils, AbstractChannel, Aes128, Block, Channel, Error, Result};
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::ristretto::RistrettoPoint;
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::Identity;
+use rand_core::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+// The maximum number of plaintext bytes in a frame.
+const FRAME_SIZE: usize = 1 << 16;
+
+// The length field of an abort frame, which carries no data.
+const ABORT: u32 = u32::MAX;
+
+// Derive the 32-byte key for one direction of the channel.
+fn kdf(label: u8, shared: &RistrettoPoint, first: &[u8], second: &[u8]) -> [u8; 32] {
+    let mut hash = Sha256::new();
+    hash.input(b"scuttlebutt secure channel");
+    hash.input([label]);
+    hash.input(shared.compress().as_bytes());
+    hash.input(first);
+    hash.input(second);
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&hash.result());
+    key
+}
+
+// Hash both parties' public keys, in the order used by `kdf`.
+fn transcript(first: &[u8], second: &[u8]) -> [u8; 32] {
+    let mut hash = Sha256::new();
+    hash.input(b"scuttlebutt secure channel transcript");
+    hash.input(first);
+    hash.input(second);
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(&hash.result());
+    digest
+}
+
+// One direction of the channel: AES-128 in counter mode, authenticated with a
+// polynomial MAC over GF(2^128). Frames are numbered implicitly, so a frame
+// that is replayed, reordered or dropped fails authentication.
+struct Cipher {
+    aes: Aes128,
+    // The MAC key.
+    hkey: Block,
+    // The sequence number of the next frame.
+    seq: u64,
+}
+
+impl Cipher {
+    fn new(key: [u8; 32]) -> Self {
+        let mut aes_key = [0u8; 16];
+        let mut hkey = [0u8; 16];
+        aes_key.copy_from_slice(&key[..16]);
+        hkey.copy_from_slice(&key[16..]);
+        Self {
+            aes: Aes128::new(Block::from(aes_key)),
+            hkey: Block::from(hkey),
+            seq: 0,
+        }
+    }
+
+    // The `i`th block of the pad for the current frame; block zero masks the
+    // tag and the rest encrypt the data.
+    #[inline]
+    fn pad(&self, i: usize) -> Block {
+        self.aes
+            .encrypt(Block::from((u128::from(self.seq) << 64) | i as u128))
+    }
+
+    #[inline]
+    fn apply_pad(&self, data: &mut [u8]) {
+        for (i, chunk) in data.chunks_mut(16).enumerate() {
+            utils::xor_inplace(chunk, self.pad(i + 1).as_ref());
+        }
+    }
+
+    // The tag of a frame, which also authenticates whether it is an abort
+    // frame.
+    fn tag(&self, ciphertext: &[u8], abort: bool) -> Block {
+        let mut acc = Block::default();
+        for chunk in ciphertext.chunks(16) {
+            let mut block = [0u8; 16];
+            block[..chunk.len()].copy_from_slice(chunk);
+            acc = (acc ^ Block::from(block)).gfmul(self.hkey);
+        }
+        let len = (u128::from(abort) << 64) | ciphertext.len() as u128;
+        acc = (acc ^ Block::from(len)).gfmul(self.hkey);
+        acc ^ self.pad(0)
+    }
+
+    #[inline]
+    fn advance(&mut self) -> Result<()> {
+        self.seq = self
+            .seq
+            .checked_add(1)
+            .ok_or_else(|| Error::misuse("secure channel sequence number exhausted"))?;
+        Ok(())
+    }
+
+    // Encrypt `data` in place, returning its tag.
+    fn seal(&mut self, data: &mut [u8], abort: bool) -> Result<Block> {
+        self.apply_pad(data);
+        let tag = self.tag(data, abort);
+        self.advance()?;
+        Ok(tag)
+    }
+
+    // Check `tag` and decrypt `data` in place.
+    fn open(&mut self, data: &mut [u8], tag: &[u8], abort: bool) -> Result<()> {
+        if !utils::ct_eq(self.tag(data, abort).as_ref(), tag) {
+            return Err(Error::verification(
+                "secure channel frame failed authentication (tampered, replayed or reordered)",
+            ));
+        }
+        self.apply_pad(data);
+        self.advance()
+    }
+}
+
+struct Session {
+    send: Cipher,
+    recv: Cipher,
+    // Plaintext waiting to be sent.
+    outgoing: Vec<u8>,
+    // The plaintext of the last frame received, of which `pos` bytes have
+    // been read.
+    incoming: Vec<u8>,
+    pos: usize,
+    // The buffer the next frame is received into, so that `incoming` is only
+    // replaced once the frame is authenticated.
+    scratch: Vec<u8>,
+    // Whether sending or receiving a frame failed, leaving the session unusable.
+    poisoned: bool,
+}
+
+impl Session {
+    #[inline]
+    fn check(&self) -> Result<()> {
+        if self.poisoned {
+            Err(Error::misuse(
+                "secure channel is unusable after an earlier error",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Poison the session if `result` is an error.
+    #[inline]
+    fn poison_on_error(&mut self, result: Result<()>) -> Result<()> {
+        if result.is_err() {
+            self.incoming.clear();
+            self.pos = 0;
+            self.poisoned = true;
+        }
+        result
+    }
+
+    fn send_frame<C: AbstractChannel>(&mut self, channel: &mut C) -> Result<()> {
+        let result = self.write_frame(channel);
+        self.poison_on_error(result)
+    }
+
+    fn write_frame<C: AbstractChannel>(&mut self, channel: &mut C) -> Result<()> {
+        let tag = self.send.seal(&mut self.outgoing, false)?;
+        let len = (self.outgoing.len() as u32).to_le_bytes();
+        channel.write_bytes_vectored(&[&len, &self.outgoing, tag.as_ref()])?;
+        self.outgoing.clear();
+        Ok(())
+    }
+
+    fn send_abort<C: AbstractChannel>(&mut self, channel: &mut C) -> Result<()> {
+        let result = self
+            .send
+            .seal(&mut [], true)
+            .and_then(|tag| channel.write_bytes_vectored(&[&ABORT.to_le_bytes(), tag.as_ref()]));
+        self.poison_on_error(result)
+    }
+
+    fn recv_frame<C: AbstractChannel>(&mut self, channel: &mut C) -> Result<()> {
+        let result = self.read_frame(channel);
+        self.poison_on_error(result)
+    }
+
+    fn read_frame<C: AbstractChannel>(&mut self, channel: &mut C) -> Result<()> {
+        let len = channel.read_u32()?;
+        if len == ABORT {
+            let mut tag = [0u8; 16];
+            channel.read_bytes(&mut tag)?;
+            self.recv.open(&mut [], &tag, true)?;
+            return Err(aborted());
+        }
+        let len = len as usize;
+        if len > FRAME_SIZE {
+            return Err(Error::decoding(format!(
+                "secure channel frame of {} bytes is too long",
+                len
+            )));
+        }
+        self.scratch.resize(len, 0);
+        channel.read_bytes(&mut self.scratch)?;
+        let mut tag = [0u8; 16];
+        channel.read_bytes(&mut tag)?;
+        self.recv.open(&mut self.scratch, &tag, false)?;
+        std::mem::swap(&mut self.incoming, &mut self.scratch);
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+/// A channel that encrypts and authenticates everything sent over it.
+///
+/// On construction, the parties run an ephemeral Diffie-Hellman key exchange
+/// over the Ristretto group and derive a key for each direction by hashing the
+/// shared point and both public keys with SHA-256. Written data is buffered
+/// and sent as frames of at most 64 KiB at each flush, each encrypted with
+/// AES-128 in counter mode and authenticated with a polynomial MAC over
+/// GF(2^128). Frames are numbered, so any tampering, replay, reordering or
+/// dropping of frames makes the read fail with `VerificationFailed`. An abort
+/// (see `AbstractChannel::abort`) is sent as an authenticated frame of its own.
+/// Once sending or receiving a frame fails, every later read, write and flush
+/// fails with `ProtocolMisuse`.
+///
+/// The key exchange is unauthenticated: it protects against eavesdroppers, but
+/// not against an active man-in-the-middle unless the parties then sign the
+/// key exchange `transcript`, e.g., with `identity::authenticate`.
+pub struct SecureChannel<R, W> {
+    channel: Channel<R, W>,
+    session: Rc<RefCell<Session>>,
+    transcript: [u8; 32],
+}
+
+impl<R: Read, W: Write> SecureChannel<R, W> {
+    /// Make a new `SecureChannel` from a `reader` and a `writer`, running the
+    /// key exchange with the other party using randomness from `rng`.
+    ///
+    /// Fails with `DecodingError` if the other party's public key is not a
+    /// valid point or is the identity, and with `ProtocolMisuse` if it is the
+    /// same as ours, e.g., when the channel is connected to itself.
+    pub fn new<RNG: CryptoRng + RngCore>(reader: R, writer: W, rng: &mut RNG) -> Result<Self> {
+        Self::from_channel(Channel::new(reader, writer), rng)
+    }
+
+    /// Make a new `SecureChannel` over `channel`, as in `new`.
+    ///
+    /// Timeouts and cancellation are controlled through `channel`, or a clone
+    /// of it kept by the caller.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn from_channel<RNG: CryptoRng + RngCore>(
+        mut channel: Channel<R, W>,
+        rng: &mut RNG,
+    ) -> Result<Self> {
+        let mut seed = [0u8; 64];
+        rng.fill_bytes(&mut seed);
+        let secret = Scalar::from_bytes_mod_order_wide(&seed);
+        let public = RISTRETTO_BASEPOINT_POINT * secret;
+        channel.write_pt(&public)?;
+        channel.flush()?;
+        let peer = channel.read_pt()?;
+        let shared = secret * peer;
+        let (ours, theirs) = (public.compress(), peer.compress());
+        if shared == RistrettoPoint::identity() {
+            return Err(Error::decoding("peer sent the identity as its public key"));
+        }
+        if ours == theirs {
+            return Err(Error::misuse("peer sent back our own public key"));
+        }
+        // The party with the smaller public key uses the first key to send.
+        let (ours, theirs) = (ours.as_bytes(), theirs.as_bytes());
+        let (send, recv, transcript) = if ours < theirs {
+            (
+                kdf(0, &shared, ours, theirs),
+                kdf(1, &shared, ours, theirs),
+                transcript(ours, theirs),
+            )
+        } else {
+            (
+                kdf(1, &shared, theirs, ours),
+                kdf(0, &shared, theirs, ours),
+                transcript(theirs, ours),
+            )
+        };
+        let session = Session {
+            send: Cipher::new(send),
+            recv: Cipher::new(recv),
+            outgoing: Vec::with_capacity(FRAME_SIZE),
+            incoming: Vec::new(),
+            pos: 0,
+            scratch: Vec::new(),
+            poisoned: false,
+        };
+        Ok(Self {
+            channel,
+            session: Rc::new(RefCell::new(session)),
+            transcript,
+        })
+    }
+
+    /// Return a digest of the key exchange, covering both parties' ephemeral
+    /// public keys. It is the same for both parties and differs from session
+    /// to session, so a party signing it binds its identity to the session
+    /// keys.
+    pub fn transcript(&self) -> [u8; 32] {
+        self.transcript
+    }
+}
+
+impl<R: Read, W: Write> AbstractChannel for SecureChannel<R, W> {
+    fn write_bytes(&mut self, mut bytes: &[u8]) -> Result<()> {
+        let mut session = self.session.borrow_mut();
+        session.check()?;
+        while !bytes.is_empty() {
+            let n = std::cmp::min(FRAME_SIZE - session.outgoing.len(), bytes.len());
+            session.outgoing.extend_from_slice(&bytes[..n]);
+            bytes = &bytes[n..];
+            if session.outgoing.len() == FRAME_SIZE {
+                session.send_frame(&mut self.channel)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, mut bytes: &mut [u8]) -> Result<()> {
+        let mut session = self.session.borrow_mut();
+        session.check()?;
+        while !bytes.is_empty() {
+            if session.pos == session.incoming.len() {
+                session.recv_frame(&mut self.channel)?;
+            }
+            let n = std::cmp::min(session.incoming.len() - session.pos, bytes.len());
+            bytes[..n].copy_from_slice(&session.incoming[session.pos..session.pos + n]);
+            session.pos += n;
+            bytes = &mut std::mem::take(&mut bytes)[n..];
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<()> {
+        let mut session = self.session.borrow_mut();
+        session.check()?;
+        if !session.outgoing.is_empty() {
+            session.send_frame(&mut self.channel)?;
+        }
+        self.channel.flush()
+    }
+
+    fn abort(&mut self) -> Result<()> {
+        self.flush()?;
+        self.session.borrow_mut().send_abort(&mut self.channel)?;
+        self.channel.flush()
+    }
+}
+
+impl<R, W> Clone for SecureChannel<R, W> {
+    fn clone(&self) -> Self {
+        Self {
+            channel: self.channel.clone(),
+            session: self.session.clone(),
+            transcript: self.transcript,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cointoss, AesRng};
+    use std::io::{BufReader, BufWriter};
+    use std::os::unix::net::UnixStream;
+
+    // A reader that flips the low bit of byte `flip` of the data following the
+    // key exchange,
+    // and replays that data once the underlying reader is exhausted.
+    struct Adversary<R> {
+        inner: R,
+        pos: usize,
+        flip: Option<usize>,
+        recorded: Vec<u8>,
+        replayed: usize,
+    }
+
+    impl<R: Read> Read for Adversary<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            if n == 0 {
+                let n = std::cmp::min(buf.len(), self.recorded.len() - self.replayed);
+                buf[..n].copy_from_slice(&self.recorded[self.replayed..self.replayed + n]);
+                self.replayed += n;
+                return Ok(n);
+            }
+            for byte in buf[..n].iter_mut() {
+                if self.pos >= 32 {
+                    if self.flip == Some(self.pos - 32) {
+                        *byte ^= 1;
+                    }
+                    self.recorded.push(*byte);
+                }
+                self.pos += 1;
+            }
+            Ok(n)
+        }
+    }
+
+    // Send `data` in a single frame through an `Adversary` flipping `flip`,
+    // returning what the receiver reads: first `data.len()` bytes and then,
+    // from the replayed frame, another `data.len()` bytes.
+    fn run(data: &'static [u8], flip: Option<usize>) -> (Result<Vec<u8>>, Result<Vec<u8>>) {
+        let (a, b) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let reader = BufReader::new(a.try_clone().unwrap());
+            let writer = BufWriter::new(a);
+            let mut channel = SecureChannel::new(reader, writer, &mut AesRng::new()).unwrap();
+            channel.write_bytes(data).unwrap();
+            channel.flush().unwrap();
+        });
+        let reader = Adversary {
+            inner: b.try_clone().unwrap(),
+            pos: 0,
+            flip,
+            recorded: Vec::new(),
+            replayed: 0,
+        };
+        let mut channel = SecureChannel::new(reader, b, &mut AesRng::new()).unwrap();
+        let mut read = || {
+            let mut received = vec![0u8; data.len()];
+            channel.read_bytes(&mut received).map(|_| received)
+        };
+        let first = read();
+        handle.join().unwrap();
+        let second = read();
+        (first, second)
+    }
+
+    #[test]
+    fn test_cointoss() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let seeds = (0..16)
+            .map(|_| rand::random::<Block>())
+            .collect::<Vec<Block>>();
+        let seeds_ = seeds.clone();
+        let handle = std::thread::spawn(move || {
+            let reader = BufReader::new(sender.try_clone().unwrap());
+            let writer = BufWriter::new(sender);
+            let mut channel = SecureChannel::new(reader, writer, &mut AesRng::new()).unwrap();
+            let data = vec![7u8; 3 * FRAME_SIZE + 1];
+            channel.write_vec(&data).unwrap();
+            cointoss::send(&mut channel, &seeds_).unwrap()
+        });
+        let reader = BufReader::new(receiver.try_clone().unwrap());
+        let writer = BufWriter::new(receiver);
+        let mut channel = SecureChannel::new(reader, writer, &mut AesRng::new()).unwrap();
+        assert_eq!(channel.read_vec().unwrap(), vec![7u8; 3 * FRAME_SIZE + 1]);
+        let output = cointoss::receive(&mut channel, &seeds).unwrap();
+        assert_eq!(output, handle.join().unwrap());
+    }
+
+    #[test]
+    fn test_abort() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let reader = BufReader::new(a.try_clone().unwrap());
+            let mut channel = SecureChannel::new(reader, a, &mut AesRng::new()).unwrap();
+            channel.write_u64(42).unwrap();
+            channel.abort().unwrap();
+        });
+        let reader = BufReader::new(b.try_clone().unwrap());
+        let mut channel = SecureChannel::new(reader, b, &mut AesRng::new()).unwrap();
+        handle.join().unwrap();
+        assert_eq!(channel.read_u64().unwrap(), 42);
+        let err = channel.read_block().unwrap_err();
+        assert!(matches!(err, Error::PeerAborted));
+    }
+
+    #[test]
+    fn test_invalid_public_key() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut channel = Channel::new(a.try_clone().unwrap(), a);
+            channel.write_pt(&RistrettoPoint::identity()).unwrap();
+            channel.flush().unwrap();
+            let _ = channel.read_pt();
+        });
+        let reader = BufReader::new(b.try_clone().unwrap());
+        let result = SecureChannel::new(reader, b, &mut AesRng::new());
+        assert!(matches!(result, Err(Error::DecodingError(_))));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_tamper() {
+        let (first, second) = run(b"hello world", Some(4 + 3));
+        assert!(matches!(first, Err(Error::VerificationFailed(_))));
+        // The rejected frame is not handed out by later reads.
+        assert!(matches!(second, Err(Error::ProtocolMisuse(_))));
+        // Tampering with the length misframes the data, which is also caught.
+        let (first, _) = run(b"hello world", Some(0));
+        assert!(matches!(first, Err(Error::VerificationFailed(_))));
+    }
+
+    #[test]
+    fn test_replay() {
+        let (first, second) = run(b"hello world", None);
+        assert_eq!(first.unwrap(), b"hello world");
+        assert!(matches!(second, Err(Error::VerificationFailed(_))));
+    }
+}
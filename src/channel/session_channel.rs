@@ -0,0 +1,501 @@
+// -*- mode: rust; -*-
+//
+// This file is part of `scuttlebutt`.
+// Copyright © 2019 Galois, Inc.
+// See LICENSE for licensing information.
+
+use super::aborted;
+use crate::{AbstractChannel, Block, Error, Result};
+use rand_core::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+// The maximum number of payload bytes in a frame.
+const FRAME_SIZE: usize = 1 << 16;
+
+const DATA: u8 = 0;
+const ACK: u8 = 1;
+const ABORT: u8 = 2;
+
+// Add a frame to a running transcript hash.
+#[inline]
+fn hash_frame(hash: &mut Sha256, seq: u64, payload: &[u8]) {
+    hash.input(seq.to_le_bytes());
+    hash.input((payload.len() as u32).to_le_bytes());
+    hash.input(payload);
+}
+
+#[inline]
+fn digest(hash: &Sha256) -> [u8; 32] {
+    let mut d = [0u8; 32];
+    d.copy_from_slice(&hash.clone().result());
+    d
+}
+
+// A frame that has been sent but not acknowledged.
+struct Frame {
+    payload: Vec<u8>,
+    // The transcript hash of everything sent up to and including this frame.
+    hash: Sha256,
+}
+
+struct Session<C> {
+    channel: C,
+    // Outgoing: `sent` frames have been sent, of which the first `acked` were
+    // acknowledged; `unacked` holds the rest, and `acked_hash` is the
+    // transcript hash of the acknowledged ones.
+    outgoing: Vec<u8>,
+    sent: u64,
+    acked: u64,
+    acked_hash: Sha256,
+    unacked: VecDeque<Frame>,
+    // Incoming: `received` frames have been received, of which the first
+    // `ack_sent` were acknowledged. `incoming` holds received data that has not
+    // been read yet, starting at `pos`.
+    received: u64,
+    received_hash: Sha256,
+    ack_sent: u64,
+    incoming: Vec<u8>,
+    pos: usize,
+    // Opens a new channel when the current one fails.
+    reconnect: Option<Box<dyn FnMut() -> Result<C>>>,
+}
+
+impl<C: AbstractChannel> Session<C> {
+    fn send_frame(&mut self) -> Result<()> {
+        let payload = std::mem::take(&mut self.outgoing);
+        let mut hash = match self.unacked.back() {
+            Some(frame) => frame.hash.clone(),
+            None => self.acked_hash.clone(),
+        };
+        hash_frame(&mut hash, self.sent, &payload);
+        self.unacked.push_back(Frame { payload, hash });
+        self.sent += 1;
+        self.write_frame(self.sent - 1)
+    }
+
+    // Write the unacknowledged frame with sequence number `seq` to the
+    // transport.
+    fn write_frame(&mut self, seq: u64) -> Result<()> {
+        let payload = &self.unacked[(seq - self.acked) as usize].payload;
+        let seq_ = seq.to_le_bytes();
+        let len = (payload.len() as u32).to_le_bytes();
+        self.channel
+            .write_bytes_vectored(&[&[DATA], &seq_, &len, payload])
+    }
+
+    // Drop the frames the peer has received, given the number it has
+    // received.
+    fn acknowledge(&mut self, count: u64) -> Result<()> {
+        if count < self.acked || count > self.sent {
+            return Err(Error::misuse(
+                "peer acknowledged frames that were never sent",
+            ));
+        }
+        while self.acked < count {
+            let frame = self.unacked.pop_front().unwrap();
+            self.acked_hash = frame.hash;
+            self.acked += 1;
+        }
+        Ok(())
+    }
+
+    // Read frames until one with data arrives.
+    fn recv_frame(&mut self) -> Result<()> {
+        loop {
+            match self.channel.read_u8()? {
+                ACK => {
+                    let count = self.channel.read_u64()?;
+                    self.acknowledge(count)?;
+                }
+                DATA => {
+                    let seq = self.channel.read_u64()?;
+                    let len = self.channel.read_u32()? as usize;
+                    if len > FRAME_SIZE {
+                        return Err(Error::decoding("session frame is too long"));
+                    }
+                    let mut payload = vec![0u8; len];
+                    self.channel.read_bytes(&mut payload)?;
+                    if seq < self.received {
+                        // A retransmission of a frame we already have.
+                        continue;
+                    }
+                    if seq > self.received {
+                        return Err(Error::misuse("session frame is missing"));
+                    }
+                    hash_frame(&mut self.received_hash, seq, &payload);
+                    self.received += 1;
+                    self.incoming.drain(..self.pos);
+                    self.pos = 0;
+                    self.incoming.extend_from_slice(&payload);
+                    return Ok(());
+                }
+                ABORT => return Err(aborted()),
+                _ => return Err(Error::decoding("invalid session frame")),
+            }
+        }
+    }
+
+    // Resume the session with ID `id` over `channel` (see
+    // `SessionChannel::resume`).
+    fn resume(&mut self, id: Block, mut channel: C) -> Result<()> {
+        channel.write_block(&id)?;
+        channel.write_u64(self.received)?;
+        channel.write_bytes(&digest(&self.received_hash))?;
+        channel.flush()?;
+        if channel.read_block()? != id {
+            return Err(Error::misuse("peer is resuming a different session"));
+        }
+        let count = channel.read_u64()?;
+        let mut peer_digest = [0u8; 32];
+        channel.read_bytes(&mut peer_digest)?;
+        if count < self.acked || count > self.sent {
+            return Err(Error::misuse("peer received frames that were never sent"));
+        }
+        if self.sent_digest(count) != peer_digest {
+            return Err(Error::verification(
+                "peer's session transcript differs from ours",
+            ));
+        }
+        let channel = std::mem::replace(&mut self.channel, channel);
+        let result = (|| {
+            for seq in count..self.sent {
+                self.write_frame(seq)?;
+            }
+            self.channel.flush()
+        })();
+        if let Err(e) = result {
+            self.channel = channel;
+            return Err(e);
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(retransmitted = self.sent - count, "session resumed");
+        self.acknowledge(count)?;
+        self.ack_sent = self.received;
+        Ok(())
+    }
+
+    // Recover from the error `e` if it is a transport error and a reconnect
+    // callback is set, by resuming over new channels until one succeeds or the
+    // callback fails; otherwise return `e`.
+    fn recover(&mut self, id: Block, mut e: Error) -> Result<()> {
+        loop {
+            let reconnect = match (&e, self.reconnect.as_mut()) {
+                (Error::IoError(_), Some(reconnect)) => reconnect,
+                _ => return Err(e),
+            };
+            #[cfg(feature = "tracing")]
+            tracing::debug!(error = %e, "session transport failed, reconnecting");
+            let channel = reconnect()?;
+            match self.resume(id, channel) {
+                Ok(()) => return Ok(()),
+                Err(e_) => e = e_,
+            }
+        }
+    }
+
+    // Acknowledge the frames received and send the buffered data.
+    fn flush(&mut self) -> Result<()> {
+        if self.received > self.ack_sent {
+            let count = self.received;
+            self.channel.write_u8(ACK)?;
+            self.channel.write_u64(count)?;
+            self.ack_sent = count;
+        }
+        if !self.outgoing.is_empty() {
+            self.send_frame()?;
+        }
+        self.channel.flush()
+    }
+
+    // The transcript digest of the first `count` frames sent.
+    fn sent_digest(&self, count: u64) -> [u8; 32] {
+        if count == self.acked {
+            digest(&self.acked_hash)
+        } else {
+            digest(&self.unacked[(count - self.acked - 1) as usize].hash)
+        }
+    }
+}
+
+/// A channel that survives transport failures.
+///
+/// Data is sent over the underlying channel as numbered frames, one per flush
+/// (or per 64 KiB of data). Each party keeps the frames it has sent until the
+/// other party acknowledges them, which it does when it next flushes. If the
+/// underlying channel fails, both parties call `resume` with a new channel
+/// (e.g., a fresh TCP connection): they exchange the session ID, how many
+/// frames they have received and a hash of those frames, check that the
+/// hashes match what was sent, and retransmit whatever was lost. With a
+/// callback set by `set_reconnect`, this happens automatically.
+///
+/// A party that only ever reads never acknowledges anything, so the other
+/// party's unacknowledged frames pile up until it does.
+pub struct SessionChannel<C> {
+    id: Block,
+    session: Rc<RefCell<Session<C>>>,
+}
+
+impl<C: AbstractChannel> SessionChannel<C> {
+    /// Start a new session over `channel`, agreeing on a session ID with the
+    /// other party using randomness from `rng`.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn new<RNG: CryptoRng + RngCore>(mut channel: C, rng: &mut RNG) -> Result<Self> {
+        let mut nonce = Block::default();
+        rng.fill_bytes(nonce.as_mut());
+        channel.write_block(&nonce)?;
+        channel.flush()?;
+        let id = nonce ^ channel.read_block()?;
+        let session = Session {
+            channel,
+            outgoing: Vec::new(),
+            sent: 0,
+            acked: 0,
+            acked_hash: Sha256::new(),
+            unacked: VecDeque::new(),
+            received: 0,
+            received_hash: Sha256::new(),
+            ack_sent: 0,
+            incoming: Vec::new(),
+            pos: 0,
+            reconnect: None,
+        };
+        Ok(Self {
+            id,
+            session: Rc::new(RefCell::new(session)),
+        })
+    }
+
+    /// Set a callback that opens a new channel to the other party (e.g., by
+    /// reconnecting over TCP), or `None` to remove it.
+    ///
+    /// With a callback set, a read, write or flush that fails with an
+    /// `IoError` calls it and resumes the session over the new channel (see
+    /// `resume`), and then carries on where it left off. If resuming fails
+    /// with an `IoError`, the callback is called again, so it should give up
+    /// (by returning an error) after some number of attempts. The other party
+    /// must also reconnect, either with its own callback or by calling
+    /// `resume`.
+    pub fn set_reconnect(&self, reconnect: Option<Box<dyn FnMut() -> Result<C>>>) {
+        self.session.borrow_mut().reconnect = reconnect;
+    }
+
+    /// Return the session ID, which is the same for both parties.
+    pub fn id(&self) -> Block {
+        self.id
+    }
+
+    /// Resume the session over `channel`, replacing the failed channel.
+    ///
+    /// Fails with `ProtocolMisuse` if the other party is in a different
+    /// session, or `VerificationFailed` if the parties disagree on what was
+    /// delivered; the session is unchanged if this fails, so it can be retried
+    /// with another channel.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(id = ?self.id))
+    )]
+    pub fn resume(&mut self, channel: C) -> Result<()> {
+        self.session.borrow_mut().resume(self.id, channel)
+    }
+}
+
+impl<C: AbstractChannel> AbstractChannel for SessionChannel<C> {
+    fn write_bytes(&mut self, mut bytes: &[u8]) -> Result<()> {
+        let mut session = self.session.borrow_mut();
+        while !bytes.is_empty() {
+            let n = std::cmp::min(FRAME_SIZE - session.outgoing.len(), bytes.len());
+            session.outgoing.extend_from_slice(&bytes[..n]);
+            bytes = &bytes[n..];
+            if session.outgoing.len() == FRAME_SIZE {
+                if let Err(e) = session.send_frame() {
+                    // The frame is retransmitted when the session is resumed.
+                    session.recover(self.id, e)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, mut bytes: &mut [u8]) -> Result<()> {
+        let mut session = self.session.borrow_mut();
+        while !bytes.is_empty() {
+            if session.pos == session.incoming.len() {
+                if let Err(e) = session.recv_frame() {
+                    // The data read so far is kept, and the rest is read once
+                    // the session is resumed.
+                    session.recover(self.id, e)?;
+                    continue;
+                }
+            }
+            let n = std::cmp::min(session.incoming.len() - session.pos, bytes.len());
+            bytes[..n].copy_from_slice(&session.incoming[session.pos..session.pos + n]);
+            session.pos += n;
+            bytes = &mut std::mem::take(&mut bytes)[n..];
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let mut session = self.session.borrow_mut();
+        // Resuming retransmits the frames sent, but data still buffered has to
+        // be sent again.
+        while let Err(e) = session.flush() {
+            session.recover(self.id, e)?;
+        }
+        Ok(())
+    }
+
+    fn abort(&mut self) -> Result<()> {
+        self.flush()?;
+        let mut session = self.session.borrow_mut();
+        session.channel.write_u8(ABORT)?;
+        session.channel.flush()
+    }
+
+    #[inline]
+    fn reject(&mut self, error: Error) -> Error {
+        self.session.borrow_mut().channel.reject(error)
+    }
+}
+
+impl<C> Clone for SessionChannel<C> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id,
+            session: self.session.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AesRng, Channel};
+    use std::io::{BufReader, BufWriter};
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+
+    type UnixChannel = Channel<BufReader<UnixStream>, BufWriter<UnixStream>>;
+
+    fn channel(stream: UnixStream) -> UnixChannel {
+        Channel::new(
+            BufReader::new(stream.try_clone().unwrap()),
+            BufWriter::new(stream),
+        )
+    }
+
+    #[test]
+    fn test_resume() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (a_, b_) = UnixStream::pair().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let handle = std::thread::spawn(move || {
+            let mut session = SessionChannel::new(channel(a), &mut AesRng::new()).unwrap();
+            session.write_u64(1).unwrap();
+            session.flush().unwrap();
+            session.write_u64(2).unwrap();
+            session.flush().unwrap();
+            // Wait for the other party to lose the connection.
+            rx.recv().unwrap();
+            session.resume(channel(a_)).unwrap();
+            session.write_u64(3).unwrap();
+            session.flush().unwrap();
+            assert_eq!(session.read_u64().unwrap(), 4);
+            session.id()
+        });
+        let mut session = SessionChannel::new(channel(b), &mut AesRng::new()).unwrap();
+        assert_eq!(session.read_u64().unwrap(), 1);
+        // The second frame is lost along with the connection, and is
+        // retransmitted on resumption.
+        tx.send(()).unwrap();
+        session.resume(channel(b_)).unwrap();
+        assert_eq!(session.read_u64().unwrap(), 2);
+        assert_eq!(session.read_u64().unwrap(), 3);
+        session.write_u64(4).unwrap();
+        session.flush().unwrap();
+        assert_eq!(handle.join().unwrap(), session.id());
+    }
+
+    #[test]
+    fn test_resume_wrong_session() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (a_, b_) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let session = SessionChannel::new(channel(a), &mut AesRng::new()).unwrap();
+            let mut other = channel(a_);
+            // Impersonate a party from another session.
+            other.write_block(&session.id().flip()).unwrap();
+            other.write_u64(0).unwrap();
+            other.write_bytes(&[0u8; 32]).unwrap();
+            other.flush().unwrap();
+            // Wait for the other party's resumption message.
+            other.read_bytes(&mut [0u8; 16 + 8 + 32]).unwrap();
+            session.id()
+        });
+        let mut session = SessionChannel::new(channel(b), &mut AesRng::new()).unwrap();
+        let err = session.resume(channel(b_)).unwrap_err();
+        assert!(matches!(err, Error::ProtocolMisuse(_)));
+        assert_eq!(handle.join().unwrap(), session.id());
+    }
+
+    #[test]
+    fn test_abort() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut session = SessionChannel::new(channel(a), &mut AesRng::new()).unwrap();
+            session.write_u64(42).unwrap();
+            session.abort().unwrap();
+        });
+        let mut session = SessionChannel::new(channel(b), &mut AesRng::new()).unwrap();
+        handle.join().unwrap();
+        assert_eq!(session.read_u64().unwrap(), 42);
+        let err = session.read_block().unwrap_err();
+        assert!(matches!(err, Error::PeerAborted));
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (a_, b_) = UnixStream::pair().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let reconnect = |stream: UnixStream| {
+            let mut stream = Some(stream);
+            Box::new(move || {
+                let stream = stream.take().ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::NotConnected, "no more connections")
+                })?;
+                Ok(channel(stream))
+            }) as Box<dyn FnMut() -> Result<UnixChannel>>
+        };
+        let handle = std::thread::spawn(move || {
+            let mut session = SessionChannel::new(channel(a), &mut AesRng::new()).unwrap();
+            session.set_reconnect(Some(reconnect(a_)));
+            session.write_u64(1).unwrap();
+            session.flush().unwrap();
+            // Wait for the connection to be lost; this flush then fails and
+            // reconnects.
+            rx.recv().unwrap();
+            session.write_u64(2).unwrap();
+            session.flush().unwrap();
+            session.id()
+        });
+        let killer = b.try_clone().unwrap();
+        let mut session = SessionChannel::new(channel(b), &mut AesRng::new()).unwrap();
+        session.set_reconnect(Some(reconnect(b_)));
+        let kill = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            killer.shutdown(std::net::Shutdown::Both).unwrap();
+            tx.send(()).unwrap();
+        });
+        // The connection is lost halfway through reading the block.
+        let mut expected = [0u8; 16];
+        expected[..8].copy_from_slice(&1u64.to_le_bytes());
+        expected[8..].copy_from_slice(&2u64.to_le_bytes());
+        assert_eq!(session.read_block().unwrap(), Block::from(expected));
+        kill.join().unwrap();
+        assert_eq!(handle.join().unwrap(), session.id());
+    }
+}
@@ -0,0 +1,414 @@
+// -*- mode: rust; -*-
+//
+// This file is part of `scuttlebutt`.
+// Copyright © 2019 Galois, Inc.
+// See LICENSE for licensing information.
+
+use super::control::Control;
+use crate::{AbstractChannel, Block, CancelHandle, Error, Result};
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const SHM_MAGIC: u64 = u64::from_le_bytes(*b"SCBTSHM1");
+
+// The layout of the shared file. Each counter gets its own cache line, and
+// the two rings (one per direction) follow the header.
+const MAGIC: usize = 0;
+const CAPACITY: usize = 8;
+const WRITE_POS: [usize; 2] = [64, 192];
+const READ_POS: [usize; 2] = [128, 256];
+const CLOSED: [usize; 2] = [320, 384];
+const HEADER_SIZE: usize = 512;
+
+// A shared memory mapping of the ring buffer file.
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+    // The capacity of each ring.
+    ring_size: usize,
+    // The party's index: `0` for the creator and `1` for the other party. Party
+    // `i` writes to ring `i` and reads from ring `1 - i`.
+    side: usize,
+    // The file to remove when the creator drops its channel.
+    path: Option<PathBuf>,
+}
+
+impl Mapping {
+    fn new(file: &File, len: usize, side: usize) -> Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(Self {
+            ptr: ptr as *mut u8,
+            len,
+            ring_size: (len - HEADER_SIZE) / 2,
+            side,
+            path: None,
+        })
+    }
+
+    #[inline]
+    fn atomic(&self, offset: usize) -> &AtomicU64 {
+        unsafe { &*(self.ptr.add(offset) as *const AtomicU64) }
+    }
+
+    #[inline]
+    fn ring(&self, ring: usize) -> *mut u8 {
+        unsafe { self.ptr.add(HEADER_SIZE + ring * self.ring_size) }
+    }
+
+    #[inline]
+    fn peer_closed(&self) -> bool {
+        self.atomic(CLOSED[1 - self.side]).load(Ordering::Acquire) != 0
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+        if let Some(path) = self.path.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// A channel between two processes on the same host, backed by a pair of ring
+/// buffers in a memory-mapped file.
+///
+/// One party calls `create` and the other `open` with the same path (e.g., a
+/// file in `/dev/shm`). Writes are visible to the other party as soon as they
+/// complete, so `flush` does nothing. Besides the usual `AbstractChannel`
+/// methods, `read_blocks_view` hands out blocks directly from the shared
+/// buffer without copying them.
+///
+/// A party waiting on the other first yields and then backs off, for at most
+/// the timeouts set with `set_read_timeout` and `set_write_timeout`, and can
+/// be cancelled from another thread through `cancel_handle`, as for `Channel`.
+///
+/// A `ShmChannel` is not `Clone`, as views into the buffer borrow the channel.
+pub struct ShmChannel {
+    mapping: Mapping,
+    // The number of bytes handed out by `read_blocks_view`, to be consumed
+    // before the next operation.
+    pending: usize,
+    scratch: Vec<Block>,
+    control: Control,
+}
+
+impl ShmChannel {
+    /// Create the file at `path` holding two ring buffers of `capacity` bytes
+    /// each, and return the creating party's end of the channel. The file is
+    /// removed when this end is dropped.
+    ///
+    /// `capacity` must be a power of two and at least 64.
+    pub fn create<P: AsRef<Path>>(path: P, capacity: usize) -> Result<Self> {
+        if !capacity.is_power_of_two() || capacity < 64 {
+            return Err(Error::misuse(
+                "ring buffer capacity must be a power of two and at least 64",
+            ));
+        }
+        // Initialize the file under a temporary name and rename it into place,
+        // so that `open` never sees it half-initialized.
+        let path = path.as_ref().to_path_buf();
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&tmp)?;
+        let len = HEADER_SIZE + 2 * capacity;
+        file.set_len(len as u64)?;
+        let mut mapping = Mapping::new(&file, len, 0)?;
+        mapping
+            .atomic(CAPACITY)
+            .store(capacity as u64, Ordering::Relaxed);
+        mapping.atomic(MAGIC).store(SHM_MAGIC, Ordering::Release);
+        std::fs::rename(&tmp, &path)?;
+        mapping.path = Some(path);
+        Ok(Self {
+            mapping,
+            pending: 0,
+            scratch: Vec::new(),
+            control: Control::default(),
+        })
+    }
+
+    /// Open the file at `path` created by the other party with `create`, and
+    /// return this party's end of the channel.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len < HEADER_SIZE {
+            return Err(Error::decoding("not a scuttlebutt shared memory channel"));
+        }
+        let mapping = Mapping::new(&file, len, 1)?;
+        let magic = mapping.atomic(MAGIC).load(Ordering::Acquire);
+        let capacity = mapping.atomic(CAPACITY).load(Ordering::Relaxed) as usize;
+        if magic != SHM_MAGIC || HEADER_SIZE + 2 * capacity != len {
+            return Err(Error::decoding("not a scuttlebutt shared memory channel"));
+        }
+        Ok(Self {
+            mapping,
+            pending: 0,
+            scratch: Vec::new(),
+            control: Control::default(),
+        })
+    }
+
+    /// Set the maximum time a single read may wait for the other party to
+    /// write, or `None` to wait forever (the default). A read that times out
+    /// fails with `TimedOut`.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) {
+        self.control.set_read_timeout(timeout)
+    }
+
+    /// Return the read timeout set by `set_read_timeout`.
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.control.read_timeout()
+    }
+
+    /// Set the maximum time a single write may wait for the other party to
+    /// free up space in the ring, or `None` to wait forever (the default).
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) {
+        self.control.set_write_timeout(timeout)
+    }
+
+    /// Return the write timeout set by `set_write_timeout`.
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.control.write_timeout()
+    }
+
+    /// Return a handle that cancels the channel, which can be sent to another
+    /// thread.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.control.cancel_handle()
+    }
+
+    // Consume the bytes handed out by the last view.
+    #[inline]
+    fn release(&mut self) {
+        if self.pending > 0 {
+            let read = self.mapping.atomic(READ_POS[1 - self.mapping.side]);
+            read.fetch_add(self.pending as u64, Ordering::Release);
+            self.pending = 0;
+        }
+    }
+
+    // Wait until at least `n` bytes can be read, returning the read position
+    // and the number of bytes available.
+    fn wait_readable(&self, n: usize) -> Result<(u64, usize)> {
+        let ring = 1 - self.mapping.side;
+        let read = self.mapping.atomic(READ_POS[ring]).load(Ordering::Relaxed);
+        let mut poll = self.control.poll(self.control.read_timeout(), "read");
+        loop {
+            let write = self.mapping.atomic(WRITE_POS[ring]).load(Ordering::Acquire);
+            let available = (write - read) as usize;
+            if available >= n {
+                return Ok((read, available));
+            }
+            // Check the write position once more after seeing the peer close,
+            // as it may have written more data just before.
+            if self.mapping.peer_closed()
+                && ((self.mapping.atomic(WRITE_POS[ring]).load(Ordering::Acquire) - read) as usize)
+                    < n
+            {
+                return Err(std::io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "the other party closed the channel",
+                )
+                .into());
+            }
+            poll.wait()?;
+        }
+    }
+
+    /// Read up to `max` blocks, waiting for at least one, and return a view of
+    /// them in the shared buffer. The blocks are consumed by the next
+    /// operation on the channel.
+    ///
+    /// The view is only copy-free when the read position is block-aligned,
+    /// which is the case if the data read so far is a multiple of 16 bytes;
+    /// otherwise the blocks are copied into an internal buffer. Fewer than
+    /// `max` blocks are returned if fewer are available or the view would
+    /// wrap around the end of the buffer.
+    pub fn read_blocks_view(&mut self, max: usize) -> Result<&[Block]> {
+        self.release();
+        self.control.check_cancelled()?;
+        if max == 0 {
+            return Ok(&[]);
+        }
+        let (read, available) = self.wait_readable(16)?;
+        let size = self.mapping.ring_size;
+        let offset = read as usize % size;
+        let nblocks = std::cmp::min(max, available / 16);
+        if !offset.is_multiple_of(16) {
+            let mut scratch = std::mem::take(&mut self.scratch);
+            scratch.resize(nblocks, Block::default());
+            self.read_blocks(&mut scratch)?;
+            self.scratch = scratch;
+            return Ok(&self.scratch);
+        }
+        let nblocks = std::cmp::min(nblocks, (size - offset) / 16);
+        self.pending = nblocks * 16;
+        let ring = self.mapping.ring(1 - self.mapping.side);
+        Ok(unsafe { std::slice::from_raw_parts(ring.add(offset) as *const Block, nblocks) })
+    }
+}
+
+impl Drop for ShmChannel {
+    fn drop(&mut self) {
+        let closed = self.mapping.atomic(CLOSED[self.mapping.side]);
+        closed.store(1, Ordering::Release);
+    }
+}
+
+impl AbstractChannel for ShmChannel {
+    fn write_bytes(&mut self, mut bytes: &[u8]) -> Result<()> {
+        self.release();
+        self.control.check_cancelled()?;
+        let ring = self.mapping.side;
+        let size = self.mapping.ring_size;
+        let write_pos = self.mapping.atomic(WRITE_POS[ring]);
+        let read_pos = self.mapping.atomic(READ_POS[ring]);
+        let data = self.mapping.ring(ring);
+        let mut poll = self.control.poll(self.control.write_timeout(), "write");
+        while !bytes.is_empty() {
+            let write = write_pos.load(Ordering::Relaxed);
+            let free = size - (write - read_pos.load(Ordering::Acquire)) as usize;
+            if free == 0 {
+                if self.mapping.peer_closed() {
+                    return Err(std::io::Error::new(
+                        ErrorKind::BrokenPipe,
+                        "the other party closed the channel",
+                    )
+                    .into());
+                }
+                poll.wait()?;
+                continue;
+            }
+            let offset = write as usize % size;
+            let n = std::cmp::min(std::cmp::min(free, size - offset), bytes.len());
+            unsafe {
+                std::ptr::copy_nonoverlapping(bytes.as_ptr(), data.add(offset), n);
+            }
+            write_pos.store(write + n as u64, Ordering::Release);
+            bytes = &bytes[n..];
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, mut bytes: &mut [u8]) -> Result<()> {
+        self.release();
+        self.control.check_cancelled()?;
+        let ring = 1 - self.mapping.side;
+        let size = self.mapping.ring_size;
+        let read_pos = self.mapping.atomic(READ_POS[ring]);
+        let data = self.mapping.ring(ring);
+        while !bytes.is_empty() {
+            let (read, available) = self.wait_readable(1)?;
+            let offset = read as usize % size;
+            let n = std::cmp::min(std::cmp::min(available, size - offset), bytes.len());
+            unsafe {
+                std::ptr::copy_nonoverlapping(data.add(offset), bytes.as_mut_ptr(), n);
+            }
+            read_pos.store(read + n as u64, Ordering::Release);
+            bytes = &mut std::mem::take(&mut bytes)[n..];
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<()> {
+        self.release();
+        self.control.check_cancelled()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NBLOCKS: usize = 1 << 14;
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("scuttlebutt-{}-{}.shm", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_blocks() {
+        let path = path("blocks");
+        let mut channel = ShmChannel::create(&path, 4096).unwrap();
+        let blocks = (0..NBLOCKS)
+            .map(|_| rand::random::<Block>())
+            .collect::<Vec<Block>>();
+        let blocks_ = blocks.clone();
+        let handle = std::thread::spawn(move || {
+            let mut channel = ShmChannel::open(&path).unwrap();
+            channel.write_blocks(&blocks_).unwrap();
+            // An unaligned write, after which views must fall back to copying.
+            channel.write_u8(42).unwrap();
+            channel.write_blocks(&blocks_[..4]).unwrap();
+            channel.read_bool().unwrap()
+        });
+        let mut received = Vec::with_capacity(NBLOCKS);
+        while received.len() < NBLOCKS {
+            let view = channel.read_blocks_view(NBLOCKS - received.len()).unwrap();
+            received.extend_from_slice(view);
+        }
+        assert_eq!(received, blocks);
+        assert_eq!(channel.read_u8().unwrap(), 42);
+        assert_eq!(channel.read_blocks_view(4).unwrap(), &blocks[..4]);
+        channel.write_bool(true).unwrap();
+        assert!(handle.join().unwrap());
+    }
+
+    #[test]
+    fn test_peer_closed() {
+        let path = path("closed");
+        let mut channel = ShmChannel::create(&path, 64).unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut channel = ShmChannel::open(&path).unwrap();
+            channel.write_u64(7).unwrap();
+        });
+        handle.join().unwrap();
+        assert_eq!(channel.read_u64().unwrap(), 7);
+        let err = channel.read_u64().unwrap_err();
+        assert!(matches!(err, Error::IoError(ref e) if e.kind() == ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_timeout_and_cancel() {
+        let path = path("timeout");
+        let mut channel = ShmChannel::create(&path, 64).unwrap();
+        let _peer = ShmChannel::open(&path).unwrap();
+        channel.set_read_timeout(Some(Duration::from_millis(20)));
+        let err = channel.read_u64().unwrap_err();
+        assert!(matches!(err, Error::IoError(ref e) if e.kind() == ErrorKind::TimedOut));
+        channel.set_read_timeout(None);
+        let handle = channel.cancel_handle();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            handle.cancel();
+        });
+        let err = channel.read_u64().unwrap_err();
+        assert!(matches!(err, Error::IoError(ref e) if e.kind() == ErrorKind::Other));
+        canceller.join().unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+// -*- mode: rust; -*-
+//
+// This file is part of `scuttlebutt`.
+// Copyright © 2019 Galois, Inc.
+// See LICENSE for licensing information.
+
+use crate::{AbstractChannel, AesRng, Channel, Result};
+use rand::Rng;
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// The network conditions modeled by a `SimChannel`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NetworkConfig {
+    /// The one-way latency.
+    pub latency: Duration,
+    /// The bandwidth, in bits per second, or `None` for unlimited bandwidth.
+    pub bandwidth: Option<u64>,
+    /// The maximum jitter; each message is delayed by an additional amount
+    /// chosen uniformly at random in `[0, jitter]`.
+    pub jitter: Duration,
+}
+
+impl NetworkConfig {
+    /// A local-area network: 0.1 ms latency and 1 Gbps bandwidth.
+    pub fn lan() -> Self {
+        Self {
+            latency: Duration::from_micros(100),
+            bandwidth: Some(1_000_000_000),
+            jitter: Duration::from_secs(0),
+        }
+    }
+
+    /// A wide-area network: 50 ms latency, 100 Mbps bandwidth and 1 ms
+    /// jitter.
+    pub fn wan() -> Self {
+        Self {
+            latency: Duration::from_millis(50),
+            bandwidth: Some(100_000_000),
+            jitter: Duration::from_millis(1),
+        }
+    }
+
+    #[inline]
+    fn transmission_time(&self, nbytes: usize) -> Duration {
+        match self.bandwidth {
+            Some(bandwidth) => Duration::from_secs_f64((nbytes * 8) as f64 / bandwidth as f64),
+            None => Duration::from_secs(0),
+        }
+    }
+}
+
+// Delays shorter than this are accumulated rather than slept, as sleeping for
+// very short durations is dominated by scheduling overhead. Any accumulated
+// delay is settled before the next flush.
+const MIN_SLEEP: Duration = Duration::from_millis(1);
+
+struct Simulation {
+    // Whether the next read starts a new incoming message.
+    new_message: bool,
+    // The simulated arrival time of the last byte read.
+    arrival: Instant,
+    // The total time slept so far.
+    delay: Duration,
+    rng: AesRng,
+}
+
+impl Simulation {
+    // Sleep until the simulated arrival time, if it is more than `threshold`
+    // in the future.
+    #[inline]
+    fn wait(&mut self, threshold: Duration) {
+        let now = Instant::now();
+        if self.arrival > now + threshold {
+            self.delay += self.arrival - now;
+            std::thread::sleep(self.arrival - now);
+        }
+    }
+}
+
+/// A channel that simulates network latency, bandwidth and jitter.
+///
+/// Each read is delayed until the simulated arrival time of the data: the
+/// first read after a flush (i.e., the start of a new incoming message) is
+/// charged the latency plus jitter, and every read is charged the time needed
+/// to transmit its bytes at the given bandwidth. Short delays are accumulated
+/// and slept in bulk, at the latest before the next flush. Since the latency is
+/// counted from when bytes are actually received, the result is an upper
+/// bound on the running time over the modeled network.
+///
+/// Both parties should use a `SimChannel` with the same `NetworkConfig`.
+pub struct SimChannel<R, W> {
+    channel: Channel<R, W>,
+    config: NetworkConfig,
+    simulation: Rc<RefCell<Simulation>>,
+}
+
+impl<R: Read, W: Write> SimChannel<R, W> {
+    /// Make a new `SimChannel` from a `reader` and a `writer`, modeling the
+    /// network conditions given by `config`.
+    pub fn new(reader: R, writer: W, config: NetworkConfig) -> Self {
+        Self::from_channel(Channel::new(reader, writer), config)
+    }
+
+    /// Make a new `SimChannel` over `channel`, modeling the network conditions
+    /// given by `config`.
+    ///
+    /// Timeouts and cancellation are controlled through `channel`, or a clone
+    /// of it kept by the caller.
+    pub fn from_channel(channel: Channel<R, W>, config: NetworkConfig) -> Self {
+        let simulation = Simulation {
+            new_message: true,
+            arrival: Instant::now(),
+            delay: Duration::from_secs(0),
+            rng: AesRng::new(),
+        };
+        Self {
+            channel,
+            config,
+            simulation: Rc::new(RefCell::new(simulation)),
+        }
+    }
+
+    /// Return the network conditions modeled by the channel.
+    pub fn config(&self) -> NetworkConfig {
+        self.config
+    }
+
+    /// Return the total time the channel has slept to simulate the network.
+    pub fn delay(&self) -> Duration {
+        self.simulation.borrow().delay
+    }
+}
+
+impl<R: Read, W: Write> AbstractChannel for SimChannel<R, W> {
+    #[inline]
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.channel.write_bytes(bytes)
+    }
+
+    #[inline]
+    fn write_bytes_vectored(&mut self, bufs: &[&[u8]]) -> Result<()> {
+        self.channel.write_bytes_vectored(bufs)
+    }
+
+    #[inline]
+    fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<()> {
+        self.channel.read_bytes(bytes)?;
+        let mut simulation = self.simulation.borrow_mut();
+        let start = std::cmp::max(Instant::now(), simulation.arrival);
+        let start = if simulation.new_message {
+            simulation.new_message = false;
+            let jitter = self.config.jitter.as_nanos() as u64;
+            let jitter = if jitter > 0 {
+                Duration::from_nanos(simulation.rng.gen_range(0, jitter + 1))
+            } else {
+                Duration::from_secs(0)
+            };
+            start + self.config.latency + jitter
+        } else {
+            start
+        };
+        simulation.arrival = start + self.config.transmission_time(bytes.len());
+        simulation.wait(MIN_SLEEP);
+        Ok(())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<()> {
+        let mut simulation = self.simulation.borrow_mut();
+        simulation.new_message = true;
+        simulation.wait(Duration::from_secs(0));
+        self.channel.flush()
+    }
+}
+
+impl<R, W> Clone for SimChannel<R, W> {
+    fn clone(&self) -> Self {
+        Self {
+            channel: self.channel.clone(),
+            config: self.config,
+            simulation: self.simulation.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cointoss, Block};
+    use std::io::{BufReader, BufWriter};
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_latency() {
+        let config = NetworkConfig {
+            latency: Duration::from_millis(20),
+            bandwidth: Some(1_000_000),
+            jitter: Duration::from_millis(1),
+        };
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let reader = BufReader::new(sender.try_clone().unwrap());
+            let writer = BufWriter::new(sender);
+            let mut channel = SimChannel::new(reader, writer, config);
+            cointoss::send(&mut channel, &[rand::random::<Block>()]).unwrap();
+        });
+        let reader = BufReader::new(receiver.try_clone().unwrap());
+        let writer = BufWriter::new(receiver);
+        let mut channel = SimChannel::new(reader, writer, config);
+        let start = Instant::now();
+        cointoss::receive(&mut channel, &[rand::random::<Block>()]).unwrap();
+        handle.join().unwrap();
+        // The receiver waits for two incoming messages, and the sender's second
+        // message is only sent after it receives the receiver's message.
+        assert!(start.elapsed() >= Duration::from_millis(60));
+        assert!(channel.delay() >= Duration::from_millis(40));
+    }
+}
@@ -0,0 +1,309 @@
+// -*- mode: rust; -*-
+//
+// This file is part of `scuttlebutt`.
+// Copyright © 2019 Galois, Inc.
+// See LICENSE for licensing information.
+
+use super::Control;
+use crate::{AbstractChannel, Error, Result};
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+#[inline]
+fn read_only() -> Error {
+    Error::misuse("cannot write to the receive half of a split channel")
+}
+
+#[inline]
+fn write_only() -> Error {
+    Error::misuse("cannot read from the send half of a split channel")
+}
+
+#[inline]
+pub(crate) fn check_halves(reader: &Arc<Control>, writer: &Arc<Control>) -> Result<()> {
+    if Arc::ptr_eq(reader, writer) {
+        Ok(())
+    } else {
+        Err(Error::misuse("cannot reunite halves of different channels"))
+    }
+}
+
+/// The receive half of a `Channel`, obtained from `Channel::split`.
+///
+/// This implements `AbstractChannel` so that all the typed `read_*` helpers are
+/// available; any attempt to write to it fails.
+pub struct ReadHalf<R> {
+    reader: Rc<RefCell<R>>,
+    control: Arc<Control>,
+}
+
+impl<R: Read> ReadHalf<R> {
+    #[inline]
+    pub(crate) fn new(reader: Rc<RefCell<R>>, control: Arc<Control>) -> Self {
+        Self { reader, control }
+    }
+
+    #[inline]
+    pub(crate) fn into_inner(self) -> (Rc<RefCell<R>>, Arc<Control>) {
+        (self.reader, self.control)
+    }
+}
+
+impl<R: Read> AbstractChannel for ReadHalf<R> {
+    #[inline]
+    fn write_bytes(&mut self, _bytes: &[u8]) -> Result<()> {
+        Err(read_only())
+    }
+
+    #[inline]
+    fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<()> {
+        self.control
+            .read_exact(&mut *self.reader.borrow_mut(), bytes)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<R> Clone for ReadHalf<R> {
+    fn clone(&self) -> Self {
+        Self {
+            reader: self.reader.clone(),
+            control: self.control.clone(),
+        }
+    }
+}
+
+/// The send half of a `Channel`, obtained from `Channel::split`.
+///
+/// This implements `AbstractChannel` so that all the typed `write_*` helpers
+/// are available; any attempt to read from it fails.
+pub struct WriteHalf<W> {
+    writer: Rc<RefCell<W>>,
+    control: Arc<Control>,
+}
+
+impl<W: Write> WriteHalf<W> {
+    #[inline]
+    pub(crate) fn new(writer: Rc<RefCell<W>>, control: Arc<Control>) -> Self {
+        Self { writer, control }
+    }
+
+    #[inline]
+    pub(crate) fn into_inner(self) -> (Rc<RefCell<W>>, Arc<Control>) {
+        (self.writer, self.control)
+    }
+}
+
+impl<W: Write> AbstractChannel for WriteHalf<W> {
+    #[inline]
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.control
+            .write_all(&mut *self.writer.borrow_mut(), bytes)
+    }
+
+    #[inline]
+    fn write_bytes_vectored(&mut self, bufs: &[&[u8]]) -> Result<()> {
+        self.control
+            .write_all_vectored(&mut *self.writer.borrow_mut(), bufs)
+    }
+
+    #[inline]
+    fn read_bytes(&mut self, _bytes: &mut [u8]) -> Result<()> {
+        Err(write_only())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<()> {
+        self.control.flush(&mut *self.writer.borrow_mut())
+    }
+}
+
+impl<W> Clone for WriteHalf<W> {
+    fn clone(&self) -> Self {
+        Self {
+            writer: self.writer.clone(),
+            control: self.control.clone(),
+        }
+    }
+}
+
+/// The receive half of a `SyncChannel`, obtained from `SyncChannel::split`.
+///
+/// Unlike `ReadHalf`, this can be sent to another thread, so that one thread
+/// reads while another writes through the matching `SyncWriteHalf`.
+pub struct SyncReadHalf<R> {
+    reader: Arc<Mutex<R>>,
+    control: Arc<Control>,
+}
+
+impl<R: Read> SyncReadHalf<R> {
+    #[inline]
+    pub(crate) fn new(reader: Arc<Mutex<R>>, control: Arc<Control>) -> Self {
+        Self { reader, control }
+    }
+
+    #[inline]
+    pub(crate) fn into_inner(self) -> (Arc<Mutex<R>>, Arc<Control>) {
+        (self.reader, self.control)
+    }
+}
+
+impl<R: Read> AbstractChannel for SyncReadHalf<R> {
+    #[inline]
+    fn write_bytes(&mut self, _bytes: &[u8]) -> Result<()> {
+        Err(read_only())
+    }
+
+    #[inline]
+    fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<()> {
+        self.control
+            .read_exact(&mut *self.reader.lock().unwrap(), bytes)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<R> Clone for SyncReadHalf<R> {
+    fn clone(&self) -> Self {
+        Self {
+            reader: self.reader.clone(),
+            control: self.control.clone(),
+        }
+    }
+}
+
+/// The send half of a `SyncChannel`, obtained from `SyncChannel::split`.
+pub struct SyncWriteHalf<W> {
+    writer: Arc<Mutex<W>>,
+    control: Arc<Control>,
+}
+
+impl<W: Write> SyncWriteHalf<W> {
+    #[inline]
+    pub(crate) fn new(writer: Arc<Mutex<W>>, control: Arc<Control>) -> Self {
+        Self { writer, control }
+    }
+
+    #[inline]
+    pub(crate) fn into_inner(self) -> (Arc<Mutex<W>>, Arc<Control>) {
+        (self.writer, self.control)
+    }
+}
+
+impl<W: Write> AbstractChannel for SyncWriteHalf<W> {
+    #[inline]
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.control
+            .write_all(&mut *self.writer.lock().unwrap(), bytes)
+    }
+
+    #[inline]
+    fn write_bytes_vectored(&mut self, bufs: &[&[u8]]) -> Result<()> {
+        self.control
+            .write_all_vectored(&mut *self.writer.lock().unwrap(), bufs)
+    }
+
+    #[inline]
+    fn read_bytes(&mut self, _bytes: &mut [u8]) -> Result<()> {
+        Err(write_only())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<()> {
+        self.control.flush(&mut *self.writer.lock().unwrap())
+    }
+}
+
+impl<W> Clone for SyncWriteHalf<W> {
+    fn clone(&self) -> Self {
+        Self {
+            writer: self.writer.clone(),
+            control: self.control.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AbstractChannel, Block, Channel, Error, SyncChannel};
+    use std::io::{BufReader, BufWriter};
+    use std::os::unix::net::UnixStream;
+
+    const NBLOCKS: usize = 1 << 16;
+
+    #[test]
+    fn test_full_duplex() {
+        let (a, b) = UnixStream::pair().unwrap();
+        // The peer echoes back every block it receives.
+        let echo = std::thread::spawn(move || {
+            let mut channel = Channel::new(BufReader::new(b.try_clone().unwrap()), b);
+            for _ in 0..NBLOCKS {
+                let block = channel.read_block().unwrap();
+                channel.write_block(&block).unwrap();
+            }
+        });
+        let channel = SyncChannel::new(BufReader::new(a.try_clone().unwrap()), BufWriter::new(a));
+        let (mut receiver, mut sender) = channel.split();
+        let blocks = (0..NBLOCKS)
+            .map(|_| rand::random::<Block>())
+            .collect::<Vec<Block>>();
+        let blocks_ = blocks.clone();
+        // Writing everything before reading anything would deadlock once the
+        // socket buffers fill up.
+        let writer = std::thread::spawn(move || {
+            sender.write_blocks(&blocks_).unwrap();
+            sender.flush().unwrap();
+            sender
+        });
+        let mut echoed = vec![Block::default(); NBLOCKS];
+        receiver.read_blocks(&mut echoed).unwrap();
+        let sender = writer.join().unwrap();
+        echo.join().unwrap();
+        assert_eq!(blocks, echoed);
+        assert!(receiver.write_bool(true).is_err());
+        let mut channel = SyncChannel::reunite(receiver, sender).unwrap();
+        channel.write_bool(true).unwrap();
+    }
+
+    #[test]
+    fn test_split_and_reunite() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let channel = Channel::new(a.try_clone().unwrap(), a);
+        let mut peer = Channel::new(b.try_clone().unwrap(), b);
+        let (mut receiver, mut sender) = channel.split();
+        let x = rand::random::<Block>();
+        sender.write_block(&x).unwrap();
+        sender.flush().unwrap();
+        assert_eq!(peer.read_block().unwrap(), x);
+        peer.write_usize(42).unwrap();
+        peer.flush().unwrap();
+        assert_eq!(receiver.read_usize().unwrap(), 42);
+        assert!(sender.read_bool().is_err());
+        let mut channel = Channel::reunite(receiver, sender).unwrap();
+        channel.write_block(&x).unwrap();
+        channel.flush().unwrap();
+        assert_eq!(peer.read_block().unwrap(), x);
+    }
+
+    #[test]
+    fn test_reunite_different_channels() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (receiver, _) = Channel::new(a.try_clone().unwrap(), a).split();
+        let (_, sender) = Channel::new(b.try_clone().unwrap(), b).split();
+        let err = Channel::reunite(receiver, sender).err().unwrap();
+        assert!(matches!(err, Error::ProtocolMisuse(_)));
+        let (a, b) = UnixStream::pair().unwrap();
+        let (receiver, _) = SyncChannel::new(a.try_clone().unwrap(), a).split();
+        let (_, sender) = SyncChannel::new(b.try_clone().unwrap(), b).split();
+        let err = SyncChannel::reunite(receiver, sender).err().unwrap();
+        assert!(matches!(err, Error::ProtocolMisuse(_)));
+    }
+}
@@ -4,14 +4,17 @@
 // Copyright © 2019 Galois, Inc.
 // See LICENSE for licensing information.
 
-use crate::AbstractChannel;
-use std::io::{Read, Result, Write};
+use super::{check_halves, CancelHandle, Control, SyncReadHalf, SyncWriteHalf};
+use crate::{AbstractChannel, Result};
+use std::io::{Read, Write};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 /// A channel that implements `AbstractChannel` as well as `Send` and `Sync`.
 pub struct SyncChannel<R, W> {
     reader: Arc<Mutex<R>>,
     writer: Arc<Mutex<W>>,
+    control: Arc<Control>,
 }
 
 impl<R: Read, W: Write> SyncChannel<R, W> {
@@ -19,7 +22,12 @@ impl<R: Read, W: Write> SyncChannel<R, W> {
     pub fn new(reader: R, writer: W) -> Self {
         let reader = Arc::new(Mutex::new(reader));
         let writer = Arc::new(Mutex::new(writer));
-        Self { reader, writer }
+        let control = Arc::new(Control::default());
+        Self {
+            reader,
+            writer,
+            control,
+        }
     }
 
     /// Return a reader object wrapped in `Arc<Mutex<R>>`.
@@ -31,30 +39,88 @@ impl<R: Read, W: Write> SyncChannel<R, W> {
     pub fn writer(self) -> Arc<Mutex<W>> {
         self.writer.clone()
     }
+
+    /// Set the read timeout; see `Channel::set_read_timeout`.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) {
+        self.control.set_read_timeout(timeout)
+    }
+
+    /// Return the read timeout set by `set_read_timeout`.
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.control.read_timeout()
+    }
+
+    /// Set the write timeout; see `Channel::set_write_timeout`.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) {
+        self.control.set_write_timeout(timeout)
+    }
+
+    /// Return the write timeout set by `set_write_timeout`.
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.control.write_timeout()
+    }
+
+    /// Return a handle that cancels the channel, which can be sent to another
+    /// thread.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.control.cancel_handle()
+    }
+
+    /// Split the channel into independent receive and send halves, which can
+    /// be used from different threads without contending for a lock.
+    pub fn split(self) -> (SyncReadHalf<R>, SyncWriteHalf<W>) {
+        (
+            SyncReadHalf::new(self.reader, self.control.clone()),
+            SyncWriteHalf::new(self.writer, self.control),
+        )
+    }
+
+    /// Reunite the halves produced by `split` into a `SyncChannel`.
+    ///
+    /// Fails with `ProtocolMisuse` if the halves come from different channels.
+    pub fn reunite(reader: SyncReadHalf<R>, writer: SyncWriteHalf<W>) -> Result<Self> {
+        let (reader, control) = reader.into_inner();
+        let (writer, control_) = writer.into_inner();
+        check_halves(&control, &control_)?;
+        Ok(Self {
+            reader,
+            writer,
+            control,
+        })
+    }
 }
 
 impl<R: Read, W: Write> AbstractChannel for SyncChannel<R, W> {
     #[inline(always)]
     fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
-        self.writer.lock().unwrap().write_all(bytes)?;
-        Ok(())
+        self.control
+            .write_all(&mut *self.writer.lock().unwrap(), bytes)
+    }
+
+    #[inline]
+    fn write_bytes_vectored(&mut self, bufs: &[&[u8]]) -> Result<()> {
+        self.control
+            .write_all_vectored(&mut *self.writer.lock().unwrap(), bufs)
     }
 
     #[inline(always)]
-    fn read_bytes(&mut self, mut bytes: &mut [u8]) -> Result<()> {
-        self.reader.lock().unwrap().read_exact(&mut bytes)
+    fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<()> {
+        self.control
+            .read_exact(&mut *self.reader.lock().unwrap(), bytes)
     }
 
     #[inline(always)]
     fn flush(&mut self) -> Result<()> {
-        self.writer.lock().unwrap().flush()
+        self.control.flush(&mut *self.writer.lock().unwrap())
     }
+}
 
-    #[inline(always)]
+impl<R, W> Clone for SyncChannel<R, W> {
     fn clone(&self) -> Self {
         Self {
             reader: self.reader.clone(),
             writer: self.writer.clone(),
+            control: self.control.clone(),
         }
     }
 }
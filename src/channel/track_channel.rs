@@ -4,68 +4,508 @@
 // Copyright © 2019 Galois, Inc.
 // See LICENSE for licensing information.
 
-use crate::{AbstractChannel, Channel};
-use std::io::{Read, Result, Write};
+use crate::{AbstractChannel, Channel, Result, SyncChannel};
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-/// A channel for tracking the number of bits read/written.
+/// Communication statistics collected by a `TrackChannel` or
+/// `SyncTrackChannel`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChannelStats {
+    /// The number of bytes written.
+    pub bytes_written: usize,
+    /// The number of bytes read.
+    pub bytes_read: usize,
+    /// The number of messages sent, where a message is all the data written
+    /// between two flushes.
+    pub messages_sent: usize,
+    /// The number of messages received, where a message is a run of reads not
+    /// interrupted by a write.
+    pub messages_received: usize,
+    /// The number of rounds, i.e., the number of times the channel switched
+    /// between writing and reading (counting the first operation).
+    pub rounds: usize,
+    /// The wall-clock time spent blocked in reads.
+    pub read_time: Duration,
+}
+
+impl ChannelStats {
+    /// Return the number of kilobits written.
+    pub fn kilobits_written(&self) -> f64 {
+        (self.bytes_written * 8) as f64 / 1000.0
+    }
+
+    /// Return the number of kilobits read.
+    pub fn kilobits_read(&self) -> f64 {
+        (self.bytes_read * 8) as f64 / 1000.0
+    }
+}
+
+impl std::ops::AddAssign<&ChannelStats> for ChannelStats {
+    fn add_assign(&mut self, rhs: &ChannelStats) {
+        self.bytes_written += rhs.bytes_written;
+        self.bytes_read += rhs.bytes_read;
+        self.messages_sent += rhs.messages_sent;
+        self.messages_received += rhs.messages_received;
+        self.rounds += rhs.rounds;
+        self.read_time += rhs.read_time;
+    }
+}
+
+impl std::fmt::Display for ChannelStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} B written / {} B read, {} messages sent / {} received, {} rounds, {:?} blocked in reads",
+            self.bytes_written,
+            self.bytes_read,
+            self.messages_sent,
+            self.messages_received,
+            self.rounds,
+            self.read_time
+        )
+    }
+}
+
+/// The name of the phase statistics are attributed to before `begin_phase` is
+/// called.
+pub const DEFAULT_PHASE: &str = "default";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Read,
+    Write,
+}
+
+// The state shared between all clones of a tracking channel.
+pub(super) struct Tracker {
+    pub(super) phases: Vec<(String, ChannelStats)>,
+    last: Option<Direction>,
+    unflushed: bool,
+}
+
+impl Tracker {
+    pub(super) fn new() -> Self {
+        Self {
+            phases: vec![(DEFAULT_PHASE.to_string(), ChannelStats::default())],
+            last: None,
+            unflushed: false,
+        }
+    }
+
+    #[inline]
+    fn current(&mut self) -> &mut ChannelStats {
+        &mut self.phases.last_mut().unwrap().1
+    }
+
+    #[inline]
+    fn switch(&mut self, direction: Direction) {
+        if self.last != Some(direction) {
+            self.last = Some(direction);
+            let stats = self.current();
+            stats.rounds += 1;
+            if direction == Direction::Read {
+                stats.messages_received += 1;
+            }
+        }
+    }
+
+    #[inline]
+    pub(super) fn wrote(&mut self, nbytes: usize) {
+        self.switch(Direction::Write);
+        self.current().bytes_written += nbytes;
+        self.unflushed |= nbytes > 0;
+    }
+
+    #[inline]
+    pub(super) fn read(&mut self, nbytes: usize, elapsed: Duration) {
+        self.switch(Direction::Read);
+        let stats = self.current();
+        stats.bytes_read += nbytes;
+        stats.read_time += elapsed;
+    }
+
+    #[inline]
+    pub(super) fn flushed(&mut self) {
+        if self.unflushed {
+            self.unflushed = false;
+            self.current().messages_sent += 1;
+            #[cfg(feature = "tracing")]
+            self.trace("message sent");
+        }
+    }
+
+    // Emit an event with the current phase's statistics.
+    #[cfg(feature = "tracing")]
+    fn trace(&self, message: &str) {
+        let (phase, stats) = self.phases.last().unwrap();
+        tracing::debug!(
+            phase = phase.as_str(),
+            bytes_written = stats.bytes_written,
+            bytes_read = stats.bytes_read,
+            rounds = stats.rounds,
+            "{}",
+            message
+        );
+    }
+
+    pub(super) fn begin_phase(&mut self, name: &str) {
+        #[cfg(feature = "tracing")]
+        self.trace("phase finished");
+        self.phases
+            .push((name.to_string(), ChannelStats::default()));
+    }
+
+    pub(super) fn total(&self) -> ChannelStats {
+        let mut total = ChannelStats::default();
+        for (_, stats) in self.phases.iter() {
+            total += stats;
+        }
+        total
+    }
+}
+
+/// A channel for tracking the communication done over it.
+///
+/// Besides byte counts, the channel records message and round counts and the
+/// time spent blocked in reads, optionally broken down into named phases (see
+/// `begin_phase`). All clones of a `TrackChannel` share the same counters.
+///
+/// With the `tracing` feature, the channel emits a `DEBUG` event carrying the
+/// current phase's byte and round counts on each flush that sends data and at
+/// the end of each phase.
 pub struct TrackChannel<R, W> {
     channel: Channel<R, W>,
-    nbits_read: usize,
-    nbits_written: usize,
+    tracker: Rc<RefCell<Tracker>>,
 }
 
 impl<R: Read, W: Write> TrackChannel<R, W> {
     /// Make a new `TrackChannel` from a `reader` and a `writer`.
     pub fn new(reader: R, writer: W) -> Self {
-        let channel = Channel::new(reader, writer);
+        Self::from_channel(Channel::new(reader, writer))
+    }
+
+    /// Make a new `TrackChannel` tracking the communication over `channel`.
+    ///
+    /// Timeouts and cancellation are controlled through `channel`, or a clone
+    /// of it kept by the caller.
+    pub fn from_channel(channel: Channel<R, W>) -> Self {
         Self {
             channel,
-            nbits_read: 0,
-            nbits_written: 0,
+            tracker: Rc::new(RefCell::new(Tracker::new())),
         }
     }
 
-    /// Clear the number of bits read/written.
+    /// Clear all statistics, including phases.
     pub fn clear(&mut self) {
-        self.nbits_read = 0;
-        self.nbits_written = 0;
+        *self.tracker.borrow_mut() = Tracker::new();
+    }
+
+    /// Attribute all subsequent communication to the phase `name`.
+    pub fn begin_phase(&mut self, name: &str) {
+        self.tracker.borrow_mut().begin_phase(name);
+    }
+
+    /// Return the statistics of each phase, in the order the phases began.
+    pub fn phases(&self) -> Vec<(String, ChannelStats)> {
+        self.tracker.borrow().phases.clone()
+    }
+
+    /// Return the statistics summed over all phases.
+    pub fn stats(&self) -> ChannelStats {
+        self.tracker.borrow().total()
     }
 
     /// Return the number of kilobits written to the channel.
     pub fn kilobits_written(&self) -> f64 {
-        self.nbits_written as f64 / 1000.0
+        self.stats().kilobits_written()
     }
 
     /// Return the number of kilobits read from the channel.
     pub fn kilobits_read(&self) -> f64 {
-        self.nbits_read as f64 / 1000.0
+        self.stats().kilobits_read()
     }
 }
 
 impl<R: Read, W: Write> AbstractChannel for TrackChannel<R, W> {
     #[inline]
     fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
-        self.nbits_written += bytes.len() * 8;
+        self.tracker.borrow_mut().wrote(bytes.len());
         self.channel.write_bytes(bytes)
     }
 
     #[inline]
-    fn read_bytes(&mut self, mut bytes: &mut [u8]) -> Result<()> {
-        self.nbits_read += bytes.len() * 8;
-        self.channel.read_bytes(&mut bytes)
+    fn write_bytes_vectored(&mut self, bufs: &[&[u8]]) -> Result<()> {
+        let nbytes = bufs.iter().map(|buf| buf.len()).sum();
+        self.tracker.borrow_mut().wrote(nbytes);
+        self.channel.write_bytes_vectored(bufs)
+    }
+
+    #[inline]
+    fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<()> {
+        let start = Instant::now();
+        self.channel.read_bytes(bytes)?;
+        self.tracker.borrow_mut().read(bytes.len(), start.elapsed());
+        Ok(())
     }
 
     #[inline]
     fn flush(&mut self) -> Result<()> {
+        self.tracker.borrow_mut().flushed();
         self.channel.flush()
     }
+}
+
+impl<R, W> Clone for TrackChannel<R, W> {
+    fn clone(&self) -> Self {
+        Self {
+            channel: self.channel.clone(),
+            tracker: self.tracker.clone(),
+        }
+    }
+}
+
+/// A `SyncChannel` which tracks the communication done over it, as in
+/// `TrackChannel`.
+///
+/// All clones of a `SyncTrackChannel` share the same counters, even across
+/// threads.
+pub struct SyncTrackChannel<R, W> {
+    channel: SyncChannel<R, W>,
+    tracker: Arc<Mutex<Tracker>>,
+}
+
+impl<R: Read, W: Write> SyncTrackChannel<R, W> {
+    /// Make a new `SyncTrackChannel` from a `reader` and a `writer`.
+    pub fn new(reader: R, writer: W) -> Self {
+        Self::from_channel(SyncChannel::new(reader, writer))
+    }
+
+    /// Make a new `SyncTrackChannel` tracking the communication over
+    /// `channel`.
+    ///
+    /// Timeouts and cancellation are controlled through `channel`, or a clone
+    /// of it kept by the caller.
+    pub fn from_channel(channel: SyncChannel<R, W>) -> Self {
+        Self {
+            channel,
+            tracker: Arc::new(Mutex::new(Tracker::new())),
+        }
+    }
+
+    /// Clear all statistics, including phases.
+    pub fn clear(&mut self) {
+        *self.tracker.lock().unwrap() = Tracker::new();
+    }
+
+    /// Attribute all subsequent communication to the phase `name`.
+    pub fn begin_phase(&mut self, name: &str) {
+        self.tracker.lock().unwrap().begin_phase(name);
+    }
+
+    /// Return the statistics of each phase, in the order the phases began.
+    pub fn phases(&self) -> Vec<(String, ChannelStats)> {
+        self.tracker.lock().unwrap().phases.clone()
+    }
+
+    /// Return the statistics summed over all phases.
+    pub fn stats(&self) -> ChannelStats {
+        self.tracker.lock().unwrap().total()
+    }
+
+    /// Return the number of kilobits written to the channel.
+    pub fn kilobits_written(&self) -> f64 {
+        self.stats().kilobits_written()
+    }
+
+    /// Return the number of kilobits read from the channel.
+    pub fn kilobits_read(&self) -> f64 {
+        self.stats().kilobits_read()
+    }
+}
+
+impl<R: Read, W: Write> AbstractChannel for SyncTrackChannel<R, W> {
+    #[inline]
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.tracker.lock().unwrap().wrote(bytes.len());
+        self.channel.write_bytes(bytes)
+    }
+
+    #[inline]
+    fn write_bytes_vectored(&mut self, bufs: &[&[u8]]) -> Result<()> {
+        let nbytes = bufs.iter().map(|buf| buf.len()).sum();
+        self.tracker.lock().unwrap().wrote(nbytes);
+        self.channel.write_bytes_vectored(bufs)
+    }
 
     #[inline]
+    fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<()> {
+        let start = Instant::now();
+        self.channel.read_bytes(bytes)?;
+        self.tracker
+            .lock()
+            .unwrap()
+            .read(bytes.len(), start.elapsed());
+        Ok(())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<()> {
+        self.tracker.lock().unwrap().flushed();
+        self.channel.flush()
+    }
+}
+
+impl<R, W> Clone for SyncTrackChannel<R, W> {
     fn clone(&self) -> Self {
         Self {
             channel: self.channel.clone(),
-            nbits_written: self.nbits_written,
-            nbits_read: self.nbits_read,
+            tracker: self.tracker.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cointoss, Block};
+    use std::io::{BufReader, BufWriter};
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_stats() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let reader = BufReader::new(sender.try_clone().unwrap());
+            let writer = BufWriter::new(sender);
+            let mut channel = Channel::new(reader, writer);
+            let _ = cointoss::send(&mut channel, &[rand::random::<Block>(); 2]).unwrap();
+        });
+        let reader = BufReader::new(receiver.try_clone().unwrap());
+        let writer = BufWriter::new(receiver);
+        let mut channel = TrackChannel::new(reader, writer);
+        let clone = channel.clone();
+        channel.begin_phase("cointoss");
+        let _ = cointoss::receive(&mut channel, &[rand::random::<Block>(); 2]).unwrap();
+        handle.join().unwrap();
+
+        let phases = clone.phases();
+        assert_eq!(phases.len(), 2);
+        assert_eq!(phases[0].0, DEFAULT_PHASE);
+        assert_eq!(phases[0].1, ChannelStats::default());
+        assert_eq!(phases[1].0, "cointoss");
+        let stats = &phases[1].1;
+        assert_eq!(stats.bytes_read, 64);
+        assert_eq!(stats.bytes_written, 32);
+        assert_eq!(stats.messages_sent, 1);
+        assert_eq!(stats.messages_received, 2);
+        assert_eq!(stats.rounds, 3);
+        assert_eq!(clone.stats(), *stats);
+        assert_eq!(clone.kilobits_read(), 0.512);
+    }
+
+    #[test]
+    fn test_sync_shared_counters() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut channel = SyncTrackChannel::new(a.try_clone().unwrap(), a);
+        let mut clone = channel.clone();
+        let handle = std::thread::spawn(move || {
+            clone.write_bytes(&[0u8; 10]).unwrap();
+            clone.flush().unwrap();
+        });
+        handle.join().unwrap();
+        channel.write_bytes(&[0u8; 5]).unwrap();
+        channel.flush().unwrap();
+        let stats = channel.stats();
+        assert_eq!(stats.bytes_written, 15);
+        assert_eq!(stats.messages_sent, 2);
+        drop(b);
+    }
+
+    #[test]
+    fn test_failed_read_not_counted() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut channel = TrackChannel::new(a.try_clone().unwrap(), a);
+        drop(b);
+        assert!(channel.read_bytes(&mut [0u8; 16]).is_err());
+        assert_eq!(channel.stats(), ChannelStats::default());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing() {
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata};
+
+        // A subscriber recording the names of the spans created and the
+        // `phase` field of each event.
+        #[derive(Clone, Default)]
+        struct Recorder {
+            spans: Arc<Mutex<Vec<&'static str>>>,
+            phases: Arc<Mutex<Vec<String>>>,
         }
+
+        struct PhaseVisitor<'a>(&'a mut Option<String>);
+
+        impl Visit for PhaseVisitor<'_> {
+            fn record_str(&mut self, field: &Field, value: &str) {
+                if field.name() == "phase" {
+                    *self.0 = Some(value.to_string());
+                }
+            }
+
+            fn record_debug(&mut self, _: &Field, _: &dyn std::fmt::Debug) {}
+        }
+
+        impl tracing::Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes) -> Id {
+                let mut spans = self.spans.lock().unwrap();
+                spans.push(span.metadata().name());
+                Id::from_u64(spans.len() as u64)
+            }
+
+            fn record(&self, _: &Id, _: &Record) {}
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, event: &Event) {
+                let mut phase = None;
+                event.record(&mut PhaseVisitor(&mut phase));
+                if let Some(phase) = phase {
+                    self.phases.lock().unwrap().push(phase);
+                }
+            }
+
+            fn enter(&self, _: &Id) {}
+
+            fn exit(&self, _: &Id) {}
+        }
+
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut channel = Channel::new(sender.try_clone().unwrap(), sender);
+            let _ = cointoss::send(&mut channel, &[rand::random::<Block>()]).unwrap();
+        });
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let mut channel = TrackChannel::new(receiver.try_clone().unwrap(), receiver);
+            channel.begin_phase("cointoss");
+            let _ = cointoss::receive(&mut channel, &[rand::random::<Block>()]).unwrap();
+            channel.begin_phase("done");
+        });
+        handle.join().unwrap();
+        assert_eq!(*recorder.spans.lock().unwrap(), vec!["receive"]);
+        assert_eq!(
+            *recorder.phases.lock().unwrap(),
+            vec![DEFAULT_PHASE, "cointoss", "cointoss"]
+        );
     }
 }
@@ -11,37 +11,38 @@
 //! receiver. It then receives `seed_` from the receiver and outputs `seed ⊕
 //! seed_`. Likewise, on input `seed`, the receiver gets `r`, sends `seed` to
 //! the sender, and then receives `seed_`, checking that `PRG(seed_) = r`.
+//!
+//! The PRG commitment relies on fixed-key AES being a good PRG; `send_with` and
+//! `receive_with` can instead use a `ShaCommitment` to the seeds (see
+//! `CommitmentScheme`). `send_rng` and `receive_rng` (and `send_rngs` and
+//! `receive_rngs`) toss a single seed and return a jointly seeded `AesRng`,
+//! for any amount of common randomness.
+//!
+//! `multiparty` generalizes this to any number of parties, each committing to
+//! its seeds with a `ShaCommitment`.
 
-use crate::{AbstractChannel, AesRng, Block};
-use rand_core::{RngCore, SeedableRng};
-
-/// Errors produced by the coin tossing protocol.
-#[derive(Debug)]
-pub enum Error {
-    /// An I/O error occurred.
-    IoError(std::io::Error),
-    /// The commitment check failed.
-    CommitmentCheckFailed,
-}
+use crate::commitment::{Commitment, ShaCommitment};
+use crate::{AbstractChannel, AesRng, Block, Error, Result};
+use rand_core::{CryptoRng, RngCore, SeedableRng};
 
-impl From<std::io::Error> for Error {
-    fn from(e: std::io::Error) -> Self {
-        Error::IoError(e)
-    }
-}
-
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Error::IoError(e) => write!(f, "IO error: {}", e),
-            Error::CommitmentCheckFailed => "committment check failed".fmt(f),
-        }
-    }
+/// The commitment used by the sender in two-party coin tossing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CommitmentScheme {
+    /// Commit to each seed with the output of `AesRng` on that seed, as `send`
+    /// and `receive` do.
+    #[default]
+    Prg,
+    /// Commit to all the seeds with a single `ShaCommitment`.
+    Sha,
 }
 
 /// Coin tossing sender.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(n = seeds.len()))
+)]
 #[inline]
-pub fn send<C: AbstractChannel>(channel: &mut C, seeds: &[Block]) -> Result<Vec<Block>, Error> {
+pub fn send<C: AbstractChannel + ?Sized>(channel: &mut C, seeds: &[Block]) -> Result<Vec<Block>> {
     let mut out = Vec::with_capacity(seeds.len());
     for seed in seeds.iter() {
         let mut rng = AesRng::from_seed(*seed);
@@ -62,8 +63,18 @@ pub fn send<C: AbstractChannel>(channel: &mut C, seeds: &[Block]) -> Result<Vec<
 }
 
 /// Coin tossing receiver.
+///
+/// If the sender's commitment check fails, the receiver aborts the channel
+/// (see `AbstractChannel::abort`) before returning `VerificationFailed`.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(n = seeds.len()))
+)]
 #[inline]
-pub fn receive<C: AbstractChannel>(channel: &mut C, seeds: &[Block]) -> Result<Vec<Block>, Error> {
+pub fn receive<C: AbstractChannel + ?Sized>(
+    channel: &mut C,
+    seeds: &[Block],
+) -> Result<Vec<Block>> {
     let mut coms = Vec::with_capacity(seeds.len());
     let mut out = Vec::with_capacity(seeds.len());
     for _ in 0..seeds.len() {
@@ -80,19 +91,340 @@ pub fn receive<C: AbstractChannel>(channel: &mut C, seeds: &[Block]) -> Result<V
         let mut check = Block::default();
         rng_.fill_bytes(&mut check.as_mut());
         if check != com {
-            return Err(Error::CommitmentCheckFailed);
+            #[cfg(feature = "tracing")]
+            tracing::warn!("coin tossing commitment check failed");
+            // Let the sender know rather than leaving it to wait on us; this is
+            // best effort, as the check failure is what we need to report.
+            let _ = channel.abort();
+            return Err(channel.reject(Error::verification("coin tossing commitment check failed")));
         }
         out.push(*seed ^ seed_)
     }
     Ok(out)
 }
 
+#[inline]
+fn seeds_bytes(seeds: &[Block]) -> Vec<u8> {
+    seeds
+        .iter()
+        .flat_map(|seed| seed.as_ref().to_vec())
+        .collect()
+}
+
+/// Coin tossing sender, using the commitment `scheme`; `rng` is used for the
+/// randomness of a `ShaCommitment`.
+pub fn send_with<C, RNG>(
+    channel: &mut C,
+    seeds: &[Block],
+    scheme: CommitmentScheme,
+    rng: &mut RNG,
+) -> Result<Vec<Block>>
+where
+    C: AbstractChannel + ?Sized,
+    RNG: CryptoRng + RngCore,
+{
+    if scheme == CommitmentScheme::Prg {
+        return send(channel, seeds);
+    }
+    let (commitment, opening) = ShaCommitment::commit(&seeds_bytes(seeds), rng);
+    channel.write_bytes(&commitment)?;
+    channel.flush()?;
+    let mut out = vec![Block::default(); seeds.len()];
+    channel.read_blocks(&mut out)?;
+    for (x, seed) in out.iter_mut().zip(seeds.iter()) {
+        *x ^= *seed;
+    }
+    channel.write_blocks(seeds)?;
+    channel.write_bytes(&opening)?;
+    channel.flush()?;
+    Ok(out)
+}
+
+/// Coin tossing receiver, using the commitment `scheme`, which must match the
+/// sender's.
+///
+/// If the sender's commitment check fails, the receiver aborts the channel
+/// before returning `VerificationFailed`.
+pub fn receive_with<C: AbstractChannel + ?Sized>(
+    channel: &mut C,
+    seeds: &[Block],
+    scheme: CommitmentScheme,
+) -> Result<Vec<Block>> {
+    if scheme == CommitmentScheme::Prg {
+        return receive(channel, seeds);
+    }
+    let mut commitment = [0u8; 32];
+    channel.read_bytes(&mut commitment)?;
+    channel.write_blocks(seeds)?;
+    channel.flush()?;
+    let mut out = vec![Block::default(); seeds.len()];
+    let mut opening = [0u8; 32];
+    channel.read_blocks(&mut out)?;
+    channel.read_bytes(&mut opening)?;
+    if let Err(e) = ShaCommitment::verify(&commitment, &seeds_bytes(&out), &opening) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!("coin tossing commitment check failed");
+        let _ = channel.abort();
+        return Err(channel.reject(e));
+    }
+    for (x, seed) in out.iter_mut().zip(seeds.iter()) {
+        *x ^= *seed;
+    }
+    Ok(out)
+}
+
+#[inline]
+fn random_seed<RNG: CryptoRng + RngCore>(rng: &mut RNG) -> Block {
+    let mut seed = Block::default();
+    rng.fill_bytes(seed.as_mut());
+    seed
+}
+
+// Fork `n` generators from the one seeded by the tossed `seed`, so that both
+// parties get the same sequence of generators.
+#[inline]
+fn fork(seed: Block, n: usize) -> Vec<AesRng> {
+    let mut rng = AesRng::from_seed(seed);
+    (0..n).map(|_| rng.fork()).collect()
+}
+
+/// Coin tossing sender, returning an `AesRng` seeded with a tossed seed, and
+/// so producing the same stream as the receiver's. The sender's seed (and the
+/// randomness of a `ShaCommitment`) is taken from `rng`.
+pub fn send_rng<C, RNG>(channel: &mut C, scheme: CommitmentScheme, rng: &mut RNG) -> Result<AesRng>
+where
+    C: AbstractChannel + ?Sized,
+    RNG: CryptoRng + RngCore,
+{
+    let seed = random_seed(rng);
+    let out = send_with(channel, &[seed], scheme, rng)?;
+    Ok(AesRng::from_seed(out[0]))
+}
+
+/// Coin tossing receiver, returning an `AesRng` that produces the same stream
+/// as the sender's. The receiver's seed is taken from `rng`.
+pub fn receive_rng<C, RNG>(
+    channel: &mut C,
+    scheme: CommitmentScheme,
+    rng: &mut RNG,
+) -> Result<AesRng>
+where
+    C: AbstractChannel + ?Sized,
+    RNG: CryptoRng + RngCore,
+{
+    let out = receive_with(channel, &[random_seed(rng)], scheme)?;
+    Ok(AesRng::from_seed(out[0]))
+}
+
+/// Like `send_rng`, but returning `n` independent generators, forked from the
+/// jointly seeded one. Only a single seed is tossed, whatever `n` is.
+pub fn send_rngs<C, RNG>(
+    channel: &mut C,
+    n: usize,
+    scheme: CommitmentScheme,
+    rng: &mut RNG,
+) -> Result<Vec<AesRng>>
+where
+    C: AbstractChannel + ?Sized,
+    RNG: CryptoRng + RngCore,
+{
+    let seed = random_seed(rng);
+    let out = send_with(channel, &[seed], scheme, rng)?;
+    Ok(fork(out[0], n))
+}
+
+/// Like `receive_rng`, but returning `n` independent generators, in the same
+/// order as the sender's `send_rngs`.
+pub fn receive_rngs<C, RNG>(
+    channel: &mut C,
+    n: usize,
+    scheme: CommitmentScheme,
+    rng: &mut RNG,
+) -> Result<Vec<AesRng>>
+where
+    C: AbstractChannel + ?Sized,
+    RNG: CryptoRng + RngCore,
+{
+    let out = receive_with(channel, &[random_seed(rng)], scheme)?;
+    Ok(fork(out[0], n))
+}
+
+// The message a party commits to: its index followed by its seeds. Including
+// the index stops a party from copying another's commitment and opening.
+fn committed_message(party: usize, seeds: &[Block]) -> Vec<u8> {
+    let mut message = (party as u64).to_le_bytes().to_vec();
+    message.extend(seeds_bytes(seeds));
+    message
+}
+
+// Best-effort abort of every channel, before reporting a cheating party.
+fn abort_all<C: AbstractChannel>(channels: &mut [Option<C>], mut error: Error) -> Error {
+    for channel in channels.iter_mut().flatten() {
+        let _ = channel.abort();
+        error = channel.reject(error);
+    }
+    error
+}
+
+// Run one round of `multiparty`: `send` writes our message to a party and
+// `receive` reads party `j`'s. The parties are taken in turn by index, and with
+// each the party with the lower index sends first, so every pair of parties
+// exchanges its messages in the same global order and no party can wait on one
+// that is itself waiting, however long the messages are.
+fn round<C, S, R>(me: usize, channels: &mut [Option<C>], mut send: S, mut receive: R) -> Result<()>
+where
+    C: AbstractChannel,
+    S: FnMut(&mut C) -> Result<()>,
+    R: FnMut(usize, &mut C) -> Result<()>,
+{
+    for (j, channel) in channels.iter_mut().enumerate() {
+        if let Some(channel) = channel {
+            if me < j {
+                send(channel)?;
+                channel.flush()?;
+                receive(j, channel)?;
+            } else {
+                receive(j, channel)?;
+                send(channel)?;
+                channel.flush()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Multi-party coin tossing, run by party `me`.
+///
+/// `channels[j]` is the channel to party `j`, for every party but `me`, whose
+/// entry must be `None`. Each party commits to its seeds and sends the
+/// commitment to every other party. The parties then echo all the commitments
+/// they received, so that a party sending different commitments to different
+/// parties is caught, and finally open their commitments; the output is the
+/// XOR of all parties' seeds, which is the same for every honest party.
+///
+/// If a check fails, the party aborts all its channels and returns
+/// `CheatingParty`. If a party's opening does not match its commitment, or it
+/// misreports our own commitment, that party is at fault. Without a broadcast
+/// channel, a mismatch in the echoed commitments of party `k` relayed by party
+/// `j` can be caused by either of them, so the error names `k` as the party
+/// and `j` as the relayer, and honest parties may then blame different
+/// parties; they never output different values.
+///
+/// # Panics
+///
+/// Panics if `channels[me]` is not `None` or another entry is `None`.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(me, n = channels.len()))
+)]
+pub fn multiparty<C, RNG>(
+    me: usize,
+    channels: &mut [Option<C>],
+    seeds: &[Block],
+    rng: &mut RNG,
+) -> Result<Vec<Block>>
+where
+    C: AbstractChannel,
+    RNG: CryptoRng + RngCore,
+{
+    assert!(
+        channels
+            .iter()
+            .enumerate()
+            .all(|(j, channel)| channel.is_none() == (j == me)),
+        "there must be a channel to every other party"
+    );
+    let n = channels.len();
+    let (commitment, opening) = ShaCommitment::commit(&committed_message(me, seeds), rng);
+    let mut commitments = vec![[0u8; 32]; n];
+    commitments[me] = commitment;
+    round(
+        me,
+        channels,
+        |channel| channel.write_bytes(&commitment),
+        |j, channel| channel.read_bytes(&mut commitments[j]),
+    )?;
+    // Echo the commitments.
+    let mut echoes = vec![vec![[0u8; 32]; n]; n];
+    round(
+        me,
+        channels,
+        |channel| {
+            for commitment in commitments.iter() {
+                channel.write_bytes(commitment)?;
+            }
+            Ok(())
+        },
+        |j, channel| {
+            for echo in echoes[j].iter_mut() {
+                channel.read_bytes(echo)?;
+            }
+            Ok(())
+        },
+    )?;
+    for j in (0..n).filter(|&j| j != me) {
+        for (k, commitment) in commitments.iter().enumerate() {
+            if echoes[j][k] != *commitment {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(party = k, relayer = j, "inconsistent echoed commitment");
+                // A party misreporting our own commitment is at fault itself.
+                let error = if k == me {
+                    Error::CheatingParty {
+                        party: j,
+                        relayer: None,
+                    }
+                } else {
+                    Error::CheatingParty {
+                        party: k,
+                        relayer: Some(j),
+                    }
+                };
+                return Err(abort_all(channels, error));
+            }
+        }
+    }
+    // Open the commitments.
+    let mut openings = vec![(vec![Block::default(); seeds.len()], [0u8; 32]); n];
+    round(
+        me,
+        channels,
+        |channel| {
+            channel.write_blocks(seeds)?;
+            channel.write_bytes(&opening)
+        },
+        |j, channel| {
+            let (seeds_, opening_) = &mut openings[j];
+            channel.read_blocks(seeds_)?;
+            channel.read_bytes(opening_)
+        },
+    )?;
+    let mut out = seeds.to_vec();
+    for j in (0..n).filter(|&j| j != me) {
+        let (seeds_, opening_) = &openings[j];
+        let message = committed_message(j, seeds_);
+        if ShaCommitment::verify(&commitments[j], &message, opening_).is_err() {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(party = j, "coin tossing commitment check failed");
+            let error = Error::CheatingParty {
+                party: j,
+                relayer: None,
+            };
+            return Err(abort_all(channels, error));
+        }
+        for (x, y) in out.iter_mut().zip(seeds_.iter()) {
+            *x ^= *y;
+        }
+    }
+    Ok(out)
+}
+
 #[cfg(test)]
 mod tests {
     #[cfg(feature = "nightly")]
     extern crate test;
     use super::*;
-    use crate::Channel;
+    use crate::{Channel, Fault, FaultChannel};
+    use rand::Rng;
     use std::io::{BufReader, BufWriter};
     use std::os::unix::net::UnixStream;
 
@@ -115,4 +447,153 @@ mod tests {
         assert_eq!(output_[0], seed ^ seed_);
         handle.join().unwrap();
     }
+
+    #[test]
+    fn test_rngs() {
+        for scheme in [CommitmentScheme::Prg, CommitmentScheme::Sha]
+            .iter()
+            .copied()
+        {
+            let (sender, receiver) = UnixStream::pair().unwrap();
+            let handle = std::thread::spawn(move || {
+                let mut channel = Channel::new(sender.try_clone().unwrap(), sender);
+                let mut rng = AesRng::new();
+                let mut rng_ = send_rng(&mut channel, scheme, &mut rng).unwrap();
+                let rngs = send_rngs(&mut channel, 3, scheme, &mut rng).unwrap();
+                (rng_.gen::<[u64; 8]>(), rngs)
+            });
+            let mut channel = Channel::new(receiver.try_clone().unwrap(), receiver);
+            let mut rng = AesRng::new();
+            let mut rng_ = receive_rng(&mut channel, scheme, &mut rng).unwrap();
+            let mut rngs = receive_rngs(&mut channel, 3, scheme, &mut rng).unwrap();
+            let (stream, mut rngs_) = handle.join().unwrap();
+            assert_eq!(rng_.gen::<[u64; 8]>(), stream);
+            let streams = rngs
+                .iter_mut()
+                .zip(rngs_.iter_mut())
+                .map(|(a, b)| {
+                    let x = a.gen::<Block>();
+                    assert_eq!(x, b.gen::<Block>());
+                    x
+                })
+                .collect::<Vec<_>>();
+            assert!(streams[0] != streams[1] && streams[1] != streams[2]);
+        }
+    }
+
+    #[test]
+    fn test_sha_commitment_check_failed() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut channel = FaultChannel::new(sender.try_clone().unwrap(), sender);
+            // Change the revealed seed, which follows the commitment.
+            channel.inject_at(1, Fault::FlipBit(0));
+            let seeds = [rand::random::<Block>()];
+            let _ = send_with(
+                &mut channel,
+                &seeds,
+                CommitmentScheme::Sha,
+                &mut AesRng::new(),
+            );
+        });
+        let mut channel = Channel::new(receiver.try_clone().unwrap(), receiver);
+        let seeds = [rand::random::<Block>()];
+        match receive_with(&mut channel, &seeds, CommitmentScheme::Sha) {
+            Err(Error::VerificationFailed(_)) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        drop(channel);
+        handle.join().unwrap();
+    }
+
+    // Run `multiparty` with `n` parties and `m` seeds each, where if `fault` is
+    // `Some((cheater, index))`, party `cheater` flips a bit of the `index`th
+    // message it sends to party 0. Party 0 is first sent the commitment, then
+    // the `n` echoed commitments and then the seeds. The seeds are chosen so
+    // that, for an even `n`, the output is `0b1...1` (with `n` ones) for each
+    // seed.
+    fn run_multiparty(
+        n: usize,
+        m: usize,
+        fault: Option<(usize, usize)>,
+    ) -> Vec<Result<Vec<Block>>> {
+        let mut streams = (0..n)
+            .map(|_| (0..n).map(|_| None).collect::<Vec<Option<UnixStream>>>())
+            .collect::<Vec<_>>();
+        for (i, j) in (0..n).flat_map(|i| (i + 1..n).map(move |j| (i, j))) {
+            let (a, b) = UnixStream::pair().unwrap();
+            streams[i][j] = Some(a);
+            streams[j][i] = Some(b);
+        }
+        let handles = streams
+            .into_iter()
+            .enumerate()
+            .map(|(i, streams)| {
+                std::thread::spawn(move || {
+                    let mut channels = streams
+                        .into_iter()
+                        .enumerate()
+                        .map(|(j, stream)| {
+                            stream.map(|s| {
+                                let reader = BufReader::new(s.try_clone().unwrap());
+                                let writer = BufWriter::new(s);
+                                match fault {
+                                    Some((cheater, index)) if cheater == i && j == 0 => {
+                                        let mut channel = FaultChannel::new(reader, writer);
+                                        channel.inject_at(index, Fault::FlipBit(0));
+                                        Box::new(channel) as Box<dyn AbstractChannel>
+                                    }
+                                    _ => Box::new(Channel::new(reader, writer)),
+                                }
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    let seeds = (0..m)
+                        .map(|k| Block::from((k as u128) << 64 | 1 << i))
+                        .collect::<Vec<_>>();
+                    multiparty(i, &mut channels, &seeds, &mut AesRng::new())
+                })
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_multiparty() {
+        for result in run_multiparty(4, 2, None) {
+            assert_eq!(result.unwrap(), vec![Block::from(0b1111); 2]);
+        }
+    }
+
+    #[test]
+    fn test_multiparty_many_seeds() {
+        // Far more than fits in the socket buffers, so that parties that all
+        // sent before reading would deadlock.
+        for result in run_multiparty(4, 1 << 16, None) {
+            assert_eq!(result.unwrap(), vec![Block::from(0b1111); 1 << 16]);
+        }
+    }
+
+    #[test]
+    fn test_multiparty_cheater() {
+        match run_multiparty(3, 2, Some((2, 4))).remove(0) {
+            Err(Error::CheatingParty {
+                party: 2,
+                relayer: None,
+            }) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_multiparty_inconsistent_echo() {
+        // Party 2 misreports party 1's commitment to party 0.
+        match run_multiparty(3, 2, Some((2, 2))).remove(0) {
+            Err(Error::CheatingParty {
+                party: 1,
+                relayer: Some(2),
+            }) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
 }
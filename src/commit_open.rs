@@ -0,0 +1,343 @@
+// -*- mode: rust; -*-
+//
+// This file is part of `scuttlebutt`.
+// Copyright © 2019 Galois, Inc.
+// See LICENSE for licensing information.
+
+//! Implementation of a two-party commit-and-open protocol over any
+//! `Commitment` scheme.
+//!
+//! The committer commits to a batch of values with `commit`, which sends the
+//! commitments, and later reveals them with `Committer::open`, which sends the
+//! values and openings. The other party reads the commitments with `receive`
+//! and checks the revealed values with `Commitments::open`. `exchange` runs
+//! both roles at once, so that each party commits to its values before seeing
+//! the other's; one party calls it as the initiator and the other as the
+//! responder.
+
+use crate::commitment::Commitment;
+#[cfg(feature = "curve25519-dalek")]
+use crate::commitment::PedersenCommitment;
+use crate::{AbstractChannel, Block, Error, Result, DEFAULT_MAX_VEC_LEN};
+#[cfg(feature = "curve25519-dalek")]
+use curve25519_dalek::scalar::Scalar;
+use rand_core::{CryptoRng, RngCore};
+use std::borrow::Borrow;
+
+/// Values that the protocol can send over a channel: messages, commitments and
+/// openings.
+pub trait Transfer: Sized {
+    /// Write the value to `channel`.
+    fn write<C: AbstractChannel + ?Sized>(&self, channel: &mut C) -> Result<()>;
+    /// Read a value from `channel`.
+    fn read<C: AbstractChannel + ?Sized>(channel: &mut C) -> Result<Self>;
+}
+
+impl Transfer for [u8; 32] {
+    fn write<C: AbstractChannel + ?Sized>(&self, channel: &mut C) -> Result<()> {
+        channel.write_bytes(self)
+    }
+
+    fn read<C: AbstractChannel + ?Sized>(channel: &mut C) -> Result<Self> {
+        let mut bytes = [0u8; 32];
+        channel.read_bytes(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+impl Transfer for Vec<u8> {
+    fn write<C: AbstractChannel + ?Sized>(&self, channel: &mut C) -> Result<()> {
+        channel.write_vec(self)
+    }
+
+    fn read<C: AbstractChannel + ?Sized>(channel: &mut C) -> Result<Self> {
+        channel.read_vec()
+    }
+}
+
+impl Transfer for Block {
+    fn write<C: AbstractChannel + ?Sized>(&self, channel: &mut C) -> Result<()> {
+        channel.write_block(self)
+    }
+
+    fn read<C: AbstractChannel + ?Sized>(channel: &mut C) -> Result<Self> {
+        channel.read_block()
+    }
+}
+
+#[cfg(feature = "curve25519-dalek")]
+impl Transfer for Scalar {
+    fn write<C: AbstractChannel + ?Sized>(&self, channel: &mut C) -> Result<()> {
+        channel.write_scalar(self)
+    }
+
+    fn read<C: AbstractChannel + ?Sized>(channel: &mut C) -> Result<Self> {
+        channel.read_scalar()
+    }
+}
+
+#[cfg(feature = "curve25519-dalek")]
+impl Transfer for Vec<Scalar> {
+    fn write<C: AbstractChannel + ?Sized>(&self, channel: &mut C) -> Result<()> {
+        channel.write_usize(self.len())?;
+        for s in self.iter() {
+            channel.write_scalar(s)?;
+        }
+        Ok(())
+    }
+
+    fn read<C: AbstractChannel + ?Sized>(channel: &mut C) -> Result<Self> {
+        let len = read_len(channel)?;
+        (0..len).map(|_| channel.read_scalar()).collect()
+    }
+}
+
+#[cfg(feature = "curve25519-dalek")]
+impl Transfer for PedersenCommitment {
+    fn write<C: AbstractChannel + ?Sized>(&self, channel: &mut C) -> Result<()> {
+        PedersenCommitment::write(self, channel)
+    }
+
+    fn read<C: AbstractChannel + ?Sized>(channel: &mut C) -> Result<Self> {
+        PedersenCommitment::read(channel)
+    }
+}
+
+// Read the number of values in a batch.
+#[inline]
+fn read_len<C: AbstractChannel + ?Sized>(channel: &mut C) -> Result<usize> {
+    let len = channel.read_usize()?;
+    if len > DEFAULT_MAX_VEC_LEN {
+        return Err(Error::decoding(format!(
+            "batch of {} values is too large",
+            len
+        )));
+    }
+    Ok(len)
+}
+
+/// The committer's state between `commit` and `Committer::open`.
+pub struct Committer<S: Commitment, M> {
+    values: Vec<M>,
+    openings: Vec<S::Opening>,
+}
+
+impl<S, M> Committer<S, M>
+where
+    S: Commitment,
+    S::Opening: Transfer,
+    M: Transfer,
+{
+    /// Reveal the committed values, returning them.
+    pub fn open<C: AbstractChannel + ?Sized>(self, channel: &mut C) -> Result<Vec<M>> {
+        for (value, opening) in self.values.iter().zip(self.openings.iter()) {
+            value.write(channel)?;
+            opening.write(channel)?;
+        }
+        channel.flush()?;
+        Ok(self.values)
+    }
+}
+
+/// The commitments received by `receive`, to be checked against the values
+/// revealed by the committer.
+pub struct Commitments<S: Commitment> {
+    commitments: Vec<S::Output>,
+}
+
+impl<S> Commitments<S>
+where
+    S: Commitment,
+    S::Opening: Transfer,
+{
+    /// Return the commitments.
+    pub fn commitments(&self) -> &[S::Output] {
+        &self.commitments
+    }
+
+    /// Read the values revealed by the committer and check them against the
+    /// commitments.
+    ///
+    /// If an opening fails, this aborts the channel (see
+    /// `AbstractChannel::abort`) and returns `OpeningFailed` with the index of
+    /// the first value that failed.
+    pub fn open<M, C>(self, channel: &mut C) -> Result<Vec<M>>
+    where
+        M: Borrow<S::Message> + Transfer,
+        C: AbstractChannel + ?Sized,
+    {
+        let mut values = Vec::with_capacity(self.commitments.len());
+        for (i, commitment) in self.commitments.iter().enumerate() {
+            let value = M::read(channel)?;
+            let opening = S::Opening::read(channel)?;
+            if S::verify(commitment, value.borrow(), &opening).is_err() {
+                let _ = channel.abort();
+                return Err(channel.reject(Error::OpeningFailed { index: i }));
+            }
+            values.push(value);
+        }
+        Ok(values)
+    }
+}
+
+/// Commit to `values`, sending the commitments over `channel`.
+pub fn commit<S, M, C, RNG>(
+    channel: &mut C,
+    values: Vec<M>,
+    rng: &mut RNG,
+) -> Result<Committer<S, M>>
+where
+    S: Commitment,
+    S::Output: Transfer,
+    M: Borrow<S::Message>,
+    C: AbstractChannel + ?Sized,
+    RNG: CryptoRng + RngCore,
+{
+    let mut openings = Vec::with_capacity(values.len());
+    channel.write_usize(values.len())?;
+    for value in values.iter() {
+        let (commitment, opening) = S::commit(value.borrow(), rng);
+        commitment.write(channel)?;
+        openings.push(opening);
+    }
+    channel.flush()?;
+    Ok(Committer { values, openings })
+}
+
+/// Receive the commitments sent by the other party's `commit`.
+pub fn receive<S, C>(channel: &mut C) -> Result<Commitments<S>>
+where
+    S: Commitment,
+    S::Output: Transfer,
+    C: AbstractChannel + ?Sized,
+{
+    let len = read_len(channel)?;
+    let commitments = (0..len)
+        .map(|_| S::Output::read(channel))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Commitments { commitments })
+}
+
+/// Commit to `values`, receive the other party's commitments, and then reveal
+/// both parties' values, returning the other party's. Exactly one of the two
+/// parties must set `initiator`.
+///
+/// The parties take turns: the initiator commits, then the responder commits,
+/// then the responder opens its commitments and finally the initiator opens
+/// its own. Each message is thus sent while the other party is waiting for it,
+/// so large batches cannot deadlock. The order also binds each party to its
+/// role: the responder commits after seeing the initiator's commitments, but
+/// must open before seeing the initiator's openings, so it cannot commit to
+/// copies of the initiator's values, and the initiator commits before seeing
+/// anything.
+///
+/// Fails with `OpeningFailed` (after aborting the channel) if one of the other
+/// party's values does not match its commitment.
+pub fn exchange<S, M, C, RNG>(
+    channel: &mut C,
+    initiator: bool,
+    values: Vec<M>,
+    rng: &mut RNG,
+) -> Result<Vec<M>>
+where
+    S: Commitment,
+    S::Output: Transfer,
+    S::Opening: Transfer,
+    M: Borrow<S::Message> + Transfer,
+    C: AbstractChannel + ?Sized,
+    RNG: CryptoRng + RngCore,
+{
+    if initiator {
+        let committer = commit::<S, M, C, RNG>(channel, values, rng)?;
+        let commitments = receive::<S, C>(channel)?;
+        let theirs = commitments.open(channel)?;
+        committer.open(channel)?;
+        Ok(theirs)
+    } else {
+        let commitments = receive::<S, C>(channel)?;
+        let committer = commit::<S, M, C, RNG>(channel, values, rng)?;
+        committer.open(channel)?;
+        commitments.open(channel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commitment::ShaCommitment;
+    use crate::{AesRng, Channel, SessionChannel};
+    use std::io::{BufReader, BufWriter};
+    use std::os::unix::net::UnixStream;
+
+    type UnixChannel = Channel<BufReader<UnixStream>, BufWriter<UnixStream>>;
+
+    fn channel(stream: UnixStream) -> UnixChannel {
+        Channel::new(
+            BufReader::new(stream.try_clone().unwrap()),
+            BufWriter::new(stream),
+        )
+    }
+
+    #[test]
+    fn test_exchange() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut a = channel(a);
+            let values = vec![b"hello".to_vec(), vec![], vec![7u8; 100]];
+            exchange::<ShaCommitment, _, _, _>(&mut a, true, values, &mut AesRng::new()).unwrap()
+        });
+        let mut b = channel(b);
+        let values = vec![b"world".to_vec()];
+        let theirs =
+            exchange::<ShaCommitment, _, _, _>(&mut b, false, values.clone(), &mut AesRng::new())
+                .unwrap();
+        assert_eq!(theirs, vec![b"hello".to_vec(), vec![], vec![7u8; 100]]);
+        assert_eq!(handle.join().unwrap(), values);
+    }
+
+    #[cfg(feature = "curve25519-dalek")]
+    #[test]
+    fn test_exchange_pedersen() {
+        use crate::commitment::Pedersen;
+        let (a, b) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut a = channel(a);
+            let values = vec![Scalar::from(1u64), Scalar::from(2u64)];
+            exchange::<Pedersen, _, _, _>(&mut a, true, values, &mut AesRng::new()).unwrap()
+        });
+        let mut b = channel(b);
+        let values = vec![Scalar::from(3u64)];
+        let theirs =
+            exchange::<Pedersen, _, _, _>(&mut b, false, values, &mut AesRng::new()).unwrap();
+        assert_eq!(theirs, vec![Scalar::from(1u64), Scalar::from(2u64)]);
+        assert_eq!(handle.join().unwrap(), vec![Scalar::from(3u64)]);
+    }
+
+    #[test]
+    fn test_opening_failed() {
+        let (a, b) = UnixStream::pair().unwrap();
+        // Aborts can only be signalled over a channel that frames its data.
+        let handle = std::thread::spawn(move || {
+            let mut a = SessionChannel::new(channel(a), &mut AesRng::new()).unwrap();
+            let values = vec![[1u8; 32], [2u8; 32]];
+            let mut committer =
+                commit::<ShaCommitment, _, _, _>(&mut a, values, &mut AesRng::new()).unwrap();
+            // Reveal a different second value.
+            committer.values[1] = [3u8; 32];
+            committer.open(&mut a).unwrap();
+            let err = a.read_block().unwrap_err();
+            assert!(matches!(err, Error::PeerAborted));
+        });
+        let mut b = SessionChannel::new(channel(b), &mut AesRng::new()).unwrap();
+        let commitments = receive::<ShaCommitment, _>(&mut b).unwrap();
+        assert_eq!(commitments.commitments().len(), 2);
+        match commitments.open::<[u8; 32], _>(&mut b) {
+            Err(e @ Error::OpeningFailed { index: 1 }) => {
+                assert_eq!(e.to_string(), "value 1 does not match its commitment")
+            }
+            _ => panic!("opening of a different value succeeded"),
+        }
+        drop(b);
+        handle.join().unwrap();
+    }
+}
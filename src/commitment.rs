@@ -4,90 +4,143 @@
 // Copyright © 2019 Galois, Inc.
 // See LICENSE for licensing information.
 
-//! A trait defining a Commitment Scheme and an implementation in the random
-//! oracle model using SHA256.
+//! A trait defining a commitment scheme, an implementation in the random
+//! oracle model using SHA256, a faster one for `Block`s using fixed-key AES,
+//! Merkle-tree commitments to vectors, and (with
+//! the `curve25519-dalek` feature) additively homomorphic Pedersen commitments
+//! over the Ristretto group.
 //!
 //! # Usage
 //! ```rust
 //! use crate::scuttlebutt::commitment::{Commitment, ShaCommitment};
+//! use crate::scuttlebutt::AesRng;
 //!
-//! // define a seed
-//! let seed = [0u8; 32];
+//! let mut rng = AesRng::new();
 //!
-//! // create a commitment object
-//! let mut commit = ShaCommitment::new(seed);
+//! // commit to a message, keeping the opening secret until it is revealed
+//! let (commitment, opening) = ShaCommitment::commit(b"hello world", &mut rng);
 //!
-//! // write input messages
+//! // check the revealed message against the commitment
+//! assert!(ShaCommitment::verify(&commitment, b"hello world", &opening).is_ok());
+//! assert!(ShaCommitment::verify(&commitment, b"hello there", &opening).is_err());
+//!
+//! // a message can also be committed to incrementally, given the opening
+//! let mut commit = ShaCommitment::new(opening);
 //! commit.input(b"hello ");
 //! commit.input(b"world");
-//!
-//! // finish commitment
-//! let commitment = commit.finish();
-//!
-//! // check a commitment
-//! let seed = [0u8; 32];
-//! let msg = b"hello world";
-//! let mut commit_ = ShaCommitment::new(seed);
-//! commit_.input(msg);
-//! let commitment_ = commit_.finish();
-//!
-//! assert!(ShaCommitment::check(&commitment,&commitment_));
+//! assert_eq!(commit.finish(), commitment);
 //! ```
 
+mod aes;
+mod merkle;
+#[cfg(feature = "curve25519-dalek")]
+mod pedersen;
+
+pub use aes::AesCommitment;
+pub use merkle::{MerkleProof, MerkleTree};
+#[cfg(feature = "curve25519-dalek")]
+pub use pedersen::{Pedersen, PedersenCommitment, PedersenGenerators, VectorPedersen};
+
+use crate::{utils, Error, Result};
+use rand_core::{CryptoRng, RngCore};
 use sha2::{Digest, Sha256};
 
 /// Generic commitment scheme.
+///
+/// The committer calls `commit`, sends the commitment, and later reveals the
+/// message along with the opening, which the other party checks with
+/// `verify`.
 pub trait Commitment {
-    /// The type used to initialize a commitment.
-    type Seed;
+    /// The type of message committed to.
+    type Message: ?Sized;
     /// The output type of the commitment.
     type Output;
+    /// The information needed to open a commitment, besides the message.
+    type Opening;
+
+    /// Commit to `msg`, using `rng` to generate the opening.
+    fn commit<RNG: CryptoRng + RngCore>(
+        msg: &Self::Message,
+        rng: &mut RNG,
+    ) -> (Self::Output, Self::Opening);
+    /// Check that `commitment` opens to `msg` with `opening`, in time
+    /// independent of the commitment's contents, failing with
+    /// `VerificationFailed` if it does not.
+    fn verify(
+        commitment: &Self::Output,
+        msg: &Self::Message,
+        opening: &Self::Opening,
+    ) -> Result<()>;
+}
 
-    /// A new commitment initialized with `seed`.
-    fn new(seed: Self::Seed) -> Self;
-    /// A method to add data to the commitment.
-    fn input(&mut self, input: &[u8]) -> ();
-    /// Complete the commitment.
-    fn finish(self) -> Self::Output;
-    /// Check if two commitments are equal.
-    fn check(comm1: &Self::Output, comm2: &Self::Output) -> bool;
+// Turn the outcome of checking a commitment into a `Result`.
+#[inline]
+fn check(ok: bool) -> Result<()> {
+    if ok {
+        Ok(())
+    } else {
+        Err(Error::verification(
+            "commitment does not open to the message",
+        ))
+    }
 }
 
-/// A commitment in the random oracle model using SHA256.
+/// A commitment in the random oracle model using SHA256: the commitment to
+/// `msg` with opening `seed` is `SHA256(msg || seed)`.
+///
+/// Besides the `Commitment` API, the commitment can be computed incrementally
+/// from a known seed with `new`, `input` and `finish`.
 pub struct ShaCommitment {
     /// The seed used to initialize the commitment.
     pub seed: [u8; 32],
     commit: Sha256,
 }
 
-impl Commitment for ShaCommitment {
-    type Seed = [u8; 32];
-    type Output = [u8; 32];
-
-    fn new(seed: Self::Seed) -> Self {
+impl ShaCommitment {
+    /// A new commitment initialized with `seed`.
+    pub fn new(seed: [u8; 32]) -> Self {
         let commit = Sha256::new();
         Self { seed, commit }
     }
 
-    fn input(&mut self, input: &[u8]) {
+    /// Add data to the commitment.
+    pub fn input(&mut self, input: &[u8]) {
         self.commit.input(input);
     }
 
-    fn finish(mut self) -> [u8; 32] {
-        self.commit.input(&self.seed);
+    /// Complete the commitment.
+    pub fn finish(mut self) -> [u8; 32] {
+        self.commit.input(self.seed);
         let mut a = [0u8; 32];
         a.copy_from_slice(&self.commit.result());
         a
     }
+}
+
+impl Commitment for ShaCommitment {
+    type Message = [u8];
+    type Output = [u8; 32];
+    type Opening = [u8; 32];
+
+    fn commit<RNG: CryptoRng + RngCore>(msg: &[u8], rng: &mut RNG) -> ([u8; 32], [u8; 32]) {
+        let mut seed = [0u8; 32];
+        rng.fill_bytes(&mut seed);
+        let mut commit = Self::new(seed);
+        commit.input(msg);
+        (commit.finish(), seed)
+    }
 
-    fn check(comm1: &Self::Output, comm2: &Self::Output) -> bool {
-        comm1 == comm2
+    fn verify(commitment: &[u8; 32], msg: &[u8], opening: &[u8; 32]) -> Result<()> {
+        let mut commit = Self::new(*opening);
+        commit.input(msg);
+        check(utils::ct_eq(&commit.finish(), commitment))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::AesRng;
     use rand::Rng;
 
     #[test]
@@ -106,27 +159,24 @@ mod tests {
 
     #[test]
     fn commit_check() {
-        // define a seed
-        let seed: _ = rand::thread_rng().gen::<[u8; 32]>();
-        let mut seed_ = [0u8; 32];
-        seed_.copy_from_slice(&seed);
-
-        // create a commitment object
-        let mut commit = ShaCommitment::new(seed);
-
-        // write input messages
-        commit.input(b"hello ");
-        commit.input(b"world");
-
-        // finish commitment
-        let commitment = commit.finish();
-
-        // check a commitment
-        let msg = b"hello world";
-        let mut commit_ = ShaCommitment::new(seed_);
-        commit_.input(msg);
-        let commitment_ = commit_.finish();
-
-        assert!(ShaCommitment::check(&commitment, &commitment_));
+        let mut rng = AesRng::new();
+        let msg = rand::thread_rng().gen::<[u8; 32]>();
+        let (commitment, opening) = ShaCommitment::commit(&msg, &mut rng);
+        assert!(ShaCommitment::verify(&commitment, &msg, &opening).is_ok());
+        let mut msg_ = msg;
+        msg_[0] ^= 1;
+        match ShaCommitment::verify(&commitment, &msg_, &opening) {
+            Err(Error::VerificationFailed(_)) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        let mut opening_ = opening;
+        opening_[31] ^= 1;
+        assert!(ShaCommitment::verify(&commitment, &msg, &opening_).is_err());
+
+        // The incremental API computes the same commitment.
+        let mut commit = ShaCommitment::new(opening);
+        commit.input(&msg[..10]);
+        commit.input(&msg[10..]);
+        assert_eq!(commit.finish(), commitment);
     }
 }
@@ -0,0 +1,332 @@
+// -*- mode: rust; -*-
+//
+// This file is part of `scuttlebutt`.
+// Copyright © 2019 Galois, Inc.
+// See LICENSE for licensing information.
+
+use crate::{AbstractChannel, Block, Error, Result, DEFAULT_MAX_VEC_LEN};
+use rand_core::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+
+// Domain separators for the three kinds of hashes in the tree.
+const LEAF: u8 = 0;
+const NODE: u8 = 1;
+const ROOT: u8 = 2;
+
+#[inline]
+fn hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hash = Sha256::new();
+    for part in parts.iter() {
+        hash.input(part);
+    }
+    let mut h = [0u8; 32];
+    h.copy_from_slice(&hash.result());
+    h
+}
+
+#[inline]
+fn hash_leaf(salt: &Block, leaf: &[u8]) -> [u8; 32] {
+    hash(&[&[LEAF], salt.as_ref(), leaf])
+}
+
+#[inline]
+fn hash_node(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    hash(&[&[NODE], left, right])
+}
+
+#[inline]
+fn hash_root(num_leaves: usize, top: &[u8; 32]) -> [u8; 32] {
+    hash(&[&[ROOT], &(num_leaves as u64).to_le_bytes(), top])
+}
+
+// Compute the root from the hashes of the leaves at `known` (sorted by
+// position), calling `sibling(level, position)` for every other node needed.
+// On each level, a final node without a sibling is carried up unchanged.
+fn walk<F>(num_leaves: usize, mut known: Vec<(usize, [u8; 32])>, mut sibling: F) -> Option<[u8; 32]>
+where
+    F: FnMut(usize, usize) -> Option<[u8; 32]>,
+{
+    let mut width = num_leaves;
+    let mut level = 0;
+    while width > 1 {
+        let mut next = Vec::with_capacity(known.len());
+        let mut i = 0;
+        while i < known.len() {
+            let (pos, h) = known[i];
+            let parent = if pos ^ 1 >= width {
+                h
+            } else if i + 1 < known.len() && known[i + 1].0 == pos ^ 1 {
+                i += 1;
+                hash_node(&h, &known[i].1)
+            } else if pos & 1 == 0 {
+                hash_node(&h, &sibling(level, pos ^ 1)?)
+            } else {
+                hash_node(&sibling(level, pos ^ 1)?, &h)
+            };
+            next.push((pos / 2, parent));
+            i += 1;
+        }
+        known = next;
+        width = width.div_ceil(2);
+        level += 1;
+    }
+    Some(hash_root(num_leaves, &known[0].1))
+}
+
+/// A Merkle tree over salted leaves, giving a commitment (the root) to a
+/// vector that can later be opened at any subset of positions.
+///
+/// Each leaf is hashed with a random 128-bit salt, so the root and the proofs
+/// reveal nothing about the unopened leaves. The root also binds the number
+/// of leaves.
+pub struct MerkleTree {
+    salts: Vec<Block>,
+    // The node hashes, level by level, starting with the leaves.
+    levels: Vec<Vec<[u8; 32]>>,
+    root: [u8; 32],
+}
+
+impl MerkleTree {
+    /// Build a tree over `leaves`, using `rng` to generate the salts.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `leaves` is empty.
+    pub fn new<T, RNG>(leaves: &[T], rng: &mut RNG) -> Self
+    where
+        T: AsRef<[u8]>,
+        RNG: CryptoRng + RngCore,
+    {
+        assert!(!leaves.is_empty(), "a Merkle tree needs at least one leaf");
+        let salts = leaves
+            .iter()
+            .map(|_| {
+                let mut salt = Block::default();
+                rng.fill_bytes(salt.as_mut());
+                salt
+            })
+            .collect::<Vec<Block>>();
+        let mut levels = vec![leaves
+            .iter()
+            .zip(salts.iter())
+            .map(|(leaf, salt)| hash_leaf(salt, leaf.as_ref()))
+            .collect::<Vec<_>>()];
+        while levels.last().unwrap().len() > 1 {
+            let level = levels.last().unwrap();
+            let next = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => hash_node(left, right),
+                    [h] => *h,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        let root = hash_root(leaves.len(), &levels.last().unwrap()[0]);
+        Self {
+            salts,
+            levels,
+            root,
+        }
+    }
+
+    /// Return the root, which commits to the leaves.
+    pub fn root(&self) -> [u8; 32] {
+        self.root
+    }
+
+    /// Return the number of leaves.
+    pub fn num_leaves(&self) -> usize {
+        self.salts.len()
+    }
+
+    /// Prove the inclusion of the leaf at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of range.
+    pub fn prove(&self, index: usize) -> MerkleProof {
+        self.prove_batch(&[index])
+    }
+
+    /// Prove the inclusion of the leaves at `indices`, which must be strictly
+    /// increasing. The proof contains each node hash needed at most once, so
+    /// it is smaller than the separate proofs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `indices` is empty, not strictly increasing or out of range.
+    pub fn prove_batch(&self, indices: &[usize]) -> MerkleProof {
+        assert!(!indices.is_empty(), "no leaves to prove");
+        assert!(
+            indices.windows(2).all(|w| w[0] < w[1]),
+            "indices must be strictly increasing"
+        );
+        assert!(
+            indices[indices.len() - 1] < self.num_leaves(),
+            "index out of range"
+        );
+        let known = indices.iter().map(|i| (*i, self.levels[0][*i])).collect();
+        let mut nodes = Vec::new();
+        walk(self.num_leaves(), known, |level, pos| {
+            nodes.push(self.levels[level][pos]);
+            Some(self.levels[level][pos])
+        });
+        MerkleProof {
+            num_leaves: self.num_leaves(),
+            salts: indices.iter().map(|i| self.salts[*i]).collect(),
+            nodes,
+        }
+    }
+}
+
+/// A proof that leaves are included in a `MerkleTree`, produced by
+/// `MerkleTree::prove` or `MerkleTree::prove_batch`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof {
+    num_leaves: usize,
+    // The salts of the opened leaves.
+    salts: Vec<Block>,
+    // The sibling hashes needed to recompute the root, in the order `walk`
+    // requests them.
+    nodes: Vec<[u8; 32]>,
+}
+
+impl MerkleProof {
+    /// Check that `leaves` are the leaves at `indices` (strictly increasing)
+    /// in the tree with root `root`, failing with `VerificationFailed` if they
+    /// are not.
+    pub fn verify<T: AsRef<[u8]>>(
+        &self,
+        root: &[u8; 32],
+        indices: &[usize],
+        leaves: &[T],
+    ) -> Result<()> {
+        let invalid = || Err(Error::verification("invalid Merkle proof"));
+        if indices.is_empty()
+            || indices.len() != leaves.len()
+            || indices.len() != self.salts.len()
+            || !indices.windows(2).all(|w| w[0] < w[1])
+            || indices[indices.len() - 1] >= self.num_leaves
+        {
+            return invalid();
+        }
+        let known = indices
+            .iter()
+            .zip(leaves.iter().zip(self.salts.iter()))
+            .map(|(i, (leaf, salt))| (*i, hash_leaf(salt, leaf.as_ref())))
+            .collect();
+        let mut nodes = self.nodes.iter();
+        match walk(self.num_leaves, known, |_, _| nodes.next().copied()) {
+            Some(root_) if nodes.next().is_none() && crate::utils::ct_eq(&root_, root) => Ok(()),
+            _ => invalid(),
+        }
+    }
+
+    /// Write the proof to `channel`.
+    pub fn write<C: AbstractChannel + ?Sized>(&self, channel: &mut C) -> Result<()> {
+        channel.write_usize(self.num_leaves)?;
+        channel.write_usize(self.salts.len())?;
+        channel.write_blocks(&self.salts)?;
+        channel.write_usize(self.nodes.len())?;
+        for node in self.nodes.iter() {
+            channel.write_bytes(node)?;
+        }
+        Ok(())
+    }
+
+    /// Read a proof written by `write` from `channel`.
+    pub fn read<C: AbstractChannel + ?Sized>(channel: &mut C) -> Result<Self> {
+        let num_leaves = channel.read_usize()?;
+        let nsalts = channel.read_usize()?;
+        if nsalts > num_leaves || nsalts > DEFAULT_MAX_VEC_LEN / 16 {
+            return Err(Error::decoding("invalid Merkle proof"));
+        }
+        let mut salts = vec![Block::default(); nsalts];
+        channel.read_blocks(&mut salts)?;
+        let nnodes = channel.read_usize()?;
+        if nnodes > DEFAULT_MAX_VEC_LEN / 32 {
+            return Err(Error::decoding("invalid Merkle proof"));
+        }
+        let mut nodes = vec![[0u8; 32]; nnodes];
+        for node in nodes.iter_mut() {
+            channel.read_bytes(node)?;
+        }
+        Ok(Self {
+            num_leaves,
+            salts,
+            nodes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AesRng, Channel};
+    use std::os::unix::net::UnixStream;
+
+    fn leaves(n: usize) -> Vec<Block> {
+        (0..n).map(|_| rand::random::<Block>()).collect()
+    }
+
+    #[test]
+    fn test_prove() {
+        let mut rng = AesRng::new();
+        for n in 1..20 {
+            let leaves = leaves(n);
+            let tree = MerkleTree::new(&leaves, &mut rng);
+            let root = tree.root();
+            for i in 0..n {
+                let proof = tree.prove(i);
+                assert!(proof.verify(&root, &[i], &[leaves[i]]).is_ok());
+                assert!(proof
+                    .verify(&root, &[i], &[leaves[(i + 1) % n] ^ Block::from(1)])
+                    .is_err());
+                assert!(n == 1 || proof.verify(&root, &[(i + 1) % n], &[leaves[i]]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_prove_batch() {
+        let mut rng = AesRng::new();
+        let leaves = leaves(13);
+        let tree = MerkleTree::new(&leaves, &mut rng);
+        let indices = [0, 3, 4, 12];
+        let opened = indices.iter().map(|i| leaves[*i]).collect::<Vec<_>>();
+        let proof = tree.prove_batch(&indices);
+        assert!(proof.verify(&tree.root(), &indices, &opened).is_ok());
+        // A batch proof is smaller than the separate proofs.
+        let separate = indices
+            .iter()
+            .map(|i| tree.prove(*i).nodes.len())
+            .sum::<usize>();
+        assert!(proof.nodes.len() < separate);
+        assert!(proof
+            .verify(&tree.root(), &indices[..3], &opened[..3])
+            .is_err());
+        let mut opened_ = opened.clone();
+        opened_[2] = leaves[5];
+        assert!(proof.verify(&tree.root(), &indices, &opened_).is_err());
+        // The salts hide the leaves: the same leaves give different roots.
+        assert_ne!(MerkleTree::new(&leaves, &mut rng).root(), tree.root());
+    }
+
+    #[test]
+    fn test_bytes_and_channel() {
+        let mut rng = AesRng::new();
+        let leaves = vec![b"hello".to_vec(), b"".to_vec(), b"world".to_vec()];
+        let tree = MerkleTree::new(&leaves, &mut rng);
+        let proof = tree.prove_batch(&[1, 2]);
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut sender = Channel::new(a.try_clone().unwrap(), a);
+        let mut receiver = Channel::new(b.try_clone().unwrap(), b);
+        proof.write(&mut sender).unwrap();
+        sender.flush().unwrap();
+        let proof_ = MerkleProof::read(&mut receiver).unwrap();
+        assert_eq!(proof_, proof);
+        assert!(proof_.verify(&tree.root(), &[1, 2], &leaves[1..]).is_ok());
+    }
+}
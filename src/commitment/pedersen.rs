@@ -0,0 +1,425 @@
+// -*- mode: rust; -*-
+//
+// This file is part of `scuttlebutt`.
+// Copyright © 2019 Galois, Inc.
+// See LICENSE for licensing information.
+
+use super::{check, Commitment};
+use crate::{AbstractChannel, Result};
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::{Identity, MultiscalarMul, VartimeMultiscalarMul};
+use rand_core::{CryptoRng, RngCore};
+use sha2::{Digest, Sha512};
+use std::ops::{Add, Mul, Sub};
+use std::sync::{Arc, Mutex, OnceLock};
+
+// The number of generators `G_i` kept for the lifetime of the program; those
+// for longer messages are derived on each use.
+const MAX_CACHED_GENERATORS: usize = 1 << 16;
+
+// Derive a generator by hashing `label` and `indices` to a point, so that
+// nobody knows its discrete logarithm with respect to any other generator.
+fn generator(label: &[u8], indices: &[u64]) -> RistrettoPoint {
+    let mut hash = Sha512::new();
+    hash.input(label);
+    for index in indices.iter() {
+        hash.input(index.to_le_bytes());
+    }
+    let mut wide = [0u8; 64];
+    wide.copy_from_slice(&hash.result());
+    RistrettoPoint::from_uniform_bytes(&wide)
+}
+
+#[inline]
+fn h() -> RistrettoPoint {
+    static H: OnceLock<RistrettoPoint> = OnceLock::new();
+    *H.get_or_init(|| generator(b"scuttlebutt pedersen H", &[0]))
+}
+
+// The generator whose coefficient in a vector commitment is the message length
+// minus one.
+#[inline]
+fn j() -> RistrettoPoint {
+    static J: OnceLock<RistrettoPoint> = OnceLock::new();
+    *J.get_or_init(|| generator(b"scuttlebutt pedersen length", &[0]))
+}
+
+#[inline]
+fn g(i: usize) -> RistrettoPoint {
+    match i {
+        0 => RISTRETTO_BASEPOINT_POINT,
+        _ => generator(b"scuttlebutt pedersen G", &[i as u64]),
+    }
+}
+
+// Call `f` with the generators `G_0, ..., G_{n-1}`. A prefix of them is cached
+// and extended as longer messages come along, up to `MAX_CACHED_GENERATORS`;
+// new generators are derived without holding the lock.
+fn with_generators<T, F: FnOnce(&[RistrettoPoint]) -> T>(n: usize, f: F) -> T {
+    static CACHE: OnceLock<Mutex<Arc<Vec<RistrettoPoint>>>> = OnceLock::new();
+    let cache = CACHE.get_or_init(Default::default);
+    let mut cached = cache.lock().unwrap().clone();
+    let wanted = std::cmp::min(n, MAX_CACHED_GENERATORS);
+    if cached.len() < wanted {
+        let mut extended = Vec::with_capacity(wanted);
+        extended.extend_from_slice(&cached);
+        extended.extend((cached.len()..wanted).map(g));
+        cached = Arc::new(extended);
+        let mut cache = cache.lock().unwrap();
+        if cache.len() < cached.len() {
+            *cache = cached.clone();
+        }
+    }
+    if n <= cached.len() {
+        f(&cached[..n])
+    } else {
+        let mut all = Vec::with_capacity(n);
+        all.extend_from_slice(&cached);
+        all.extend((cached.len()..n).map(g));
+        f(&all)
+    }
+}
+
+// Compute `m_0 G_0 + ... + m_{n-1} G_{n-1} + r H + (n - 1) J`.
+#[inline]
+fn vector_commitment(g: &[RistrettoPoint], msg: &[Scalar], opening: &Scalar) -> PedersenCommitment {
+    let len = Scalar::from(msg.len() as u64) - Scalar::one();
+    PedersenCommitment(RistrettoPoint::multiscalar_mul(
+        msg.iter().chain([*opening, len].iter()),
+        g.iter().chain([h(), j()].iter()),
+    ))
+}
+
+#[inline]
+fn random_scalar<RNG: CryptoRng + RngCore>(rng: &mut RNG) -> Scalar {
+    let mut wide = [0u8; 64];
+    rng.fill_bytes(&mut wide);
+    Scalar::from_bytes_mod_order_wide(&wide)
+}
+
+/// A Pedersen commitment.
+///
+/// Commitments are additively homomorphic: the sum of commitments to `m` and
+/// `m_` with openings `r` and `r_` is a commitment to `m + m_` with opening
+/// `r + r_`, and multiplying a commitment by `k` gives a commitment to `k * m`
+/// with opening `k * r`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PedersenCommitment(RistrettoPoint);
+
+impl PedersenCommitment {
+    /// Encode the commitment as bytes.
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.compress().to_bytes()
+    }
+
+    /// Decode a commitment produced by `to_bytes`, returning `None` if the
+    /// encoding is invalid.
+    pub fn from_bytes(bytes: &[u8; 32]) -> Option<Self> {
+        CompressedRistretto(*bytes)
+            .decompress()
+            .map(PedersenCommitment)
+    }
+
+    /// Write the commitment to `channel`.
+    pub fn write<C: AbstractChannel + ?Sized>(&self, channel: &mut C) -> Result<()> {
+        channel.write_pt(&self.0)
+    }
+
+    /// Read a commitment from `channel`, failing with `DecodingError` if its
+    /// encoding is invalid.
+    pub fn read<C: AbstractChannel + ?Sized>(channel: &mut C) -> Result<Self> {
+        channel.read_pt().map(PedersenCommitment)
+    }
+}
+
+impl Add for PedersenCommitment {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        PedersenCommitment(self.0 + rhs.0)
+    }
+}
+
+impl Sub for PedersenCommitment {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        PedersenCommitment(self.0 - rhs.0)
+    }
+}
+
+impl Mul<Scalar> for PedersenCommitment {
+    type Output = Self;
+
+    fn mul(self, rhs: Scalar) -> Self {
+        PedersenCommitment(self.0 * rhs)
+    }
+}
+
+/// The generators for vector Pedersen commitments to `capacity` scalars.
+///
+/// The commitment to `m_0, ..., m_{n-1}` with opening `r` is `m_0 G_0 + ... +
+/// m_{n-1} G_{n-1} + r H + (n - 1) J`. `G_0` is the Ristretto basepoint, and
+/// the other generators are derived by hashing fixed labels (and `i`) to
+/// points. The `J` term binds the length of the message, so a message cannot
+/// be opened as a zero-padded one, and vanishes for `n = 1`, so a commitment
+/// to a single scalar is an ordinary Pedersen commitment. Generating the `G_i`
+/// costs a hash-to-point each; the first `2^16` are cached for the lifetime of
+/// the program.
+#[derive(Clone, Debug)]
+pub struct PedersenGenerators {
+    g: Vec<RistrettoPoint>,
+}
+
+impl PedersenGenerators {
+    /// Derive the generators for commitments to `capacity` scalars.
+    pub fn new(capacity: usize) -> Self {
+        let g = with_generators(capacity, |g| g.to_vec());
+        Self { g }
+    }
+
+    /// Return the number of scalars that are committed to.
+    pub fn capacity(&self) -> usize {
+        self.g.len()
+    }
+
+    /// Commit to `msg` with opening `opening`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of `msg` is not `capacity()`.
+    pub fn commit_with_opening(&self, msg: &[Scalar], opening: &Scalar) -> PedersenCommitment {
+        assert_eq!(
+            msg.len(),
+            self.g.len(),
+            "message length does not match the generators"
+        );
+        vector_commitment(&self.g, msg, opening)
+    }
+
+    /// Commit to `msg`, using `rng` to generate the opening.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of `msg` is not `capacity()`.
+    pub fn commit<RNG: CryptoRng + RngCore>(
+        &self,
+        msg: &[Scalar],
+        rng: &mut RNG,
+    ) -> (PedersenCommitment, Scalar) {
+        let opening = random_scalar(rng);
+        (self.commit_with_opening(msg, &opening), opening)
+    }
+
+    /// Check that `commitment` opens to `msg` with `opening`, failing with
+    /// `VerificationFailed` if it does not or if the length of `msg` is not
+    /// `capacity()`.
+    pub fn verify(
+        &self,
+        commitment: &PedersenCommitment,
+        msg: &[Scalar],
+        opening: &Scalar,
+    ) -> Result<()> {
+        check(msg.len() == self.g.len() && self.commit_with_opening(msg, opening) == *commitment)
+    }
+
+    /// Check many openings at once, which is much faster than calling `verify`
+    /// on each. Each item is a commitment, message and opening.
+    ///
+    /// This checks a random linear combination of the openings (with weights
+    /// from `rng`), so it fails to detect an invalid opening with negligible
+    /// probability. It fails outright if the length of any message is not
+    /// `capacity()`.
+    pub fn batch_verify<RNG: CryptoRng + RngCore>(
+        &self,
+        items: &[(PedersenCommitment, &[Scalar], Scalar)],
+        rng: &mut RNG,
+    ) -> Result<()> {
+        let n = self.g.len();
+        if items.iter().any(|(_, msg, _)| msg.len() != n) {
+            return check(false);
+        }
+        // Check that `sum_k z_k (m_k G + r_k H + (n - 1) J - C_k)` is the
+        // identity.
+        let len = Scalar::from(n as u64) - Scalar::one();
+        let mut scalars = vec![Scalar::zero(); n + 2];
+        let mut points = self.g.clone();
+        points.push(h());
+        points.push(j());
+        for (commitment, msg, opening) in items.iter() {
+            let z = random_scalar(rng);
+            for (s, m) in scalars.iter_mut().zip(msg.iter()) {
+                *s += z * m;
+            }
+            scalars[n] += z * opening;
+            scalars[n + 1] += z * len;
+            scalars.push(-z);
+            points.push(commitment.0);
+        }
+        check(
+            RistrettoPoint::vartime_multiscalar_mul(scalars.iter(), points.iter())
+                == RistrettoPoint::identity(),
+        )
+    }
+}
+
+/// Pedersen commitments to a single `Scalar`: the commitment to `m` with
+/// opening `r` is `m G + r H`, where `G` is the Ristretto basepoint and `H` is
+/// derived by hashing a fixed label to a point.
+pub struct Pedersen;
+
+impl Pedersen {
+    /// Check many openings at once, as in `PedersenGenerators::batch_verify`.
+    pub fn batch_verify<RNG: CryptoRng + RngCore>(
+        items: &[(PedersenCommitment, Scalar, Scalar)],
+        rng: &mut RNG,
+    ) -> Result<()> {
+        let items = items
+            .iter()
+            .map(|(commitment, msg, opening)| (*commitment, std::slice::from_ref(msg), *opening))
+            .collect::<Vec<_>>();
+        PedersenGenerators::new(1).batch_verify(&items, rng)
+    }
+}
+
+impl Commitment for Pedersen {
+    type Message = Scalar;
+    type Output = PedersenCommitment;
+    type Opening = Scalar;
+
+    fn commit<RNG: CryptoRng + RngCore>(
+        msg: &Scalar,
+        rng: &mut RNG,
+    ) -> (PedersenCommitment, Scalar) {
+        let opening = random_scalar(rng);
+        let commitment =
+            RistrettoPoint::multiscalar_mul(&[*msg, opening], &[RISTRETTO_BASEPOINT_POINT, h()]);
+        (PedersenCommitment(commitment), opening)
+    }
+
+    fn verify(commitment: &PedersenCommitment, msg: &Scalar, opening: &Scalar) -> Result<()> {
+        let commitment_ =
+            RistrettoPoint::multiscalar_mul(&[*msg, *opening], &[RISTRETTO_BASEPOINT_POINT, h()]);
+        check(commitment_ == commitment.0)
+    }
+}
+
+/// Vector Pedersen commitments to a slice of `Scalar`s, as with the
+/// `PedersenGenerators` for the length of the slice, sharing their cache.
+pub struct VectorPedersen;
+
+impl Commitment for VectorPedersen {
+    type Message = [Scalar];
+    type Output = PedersenCommitment;
+    type Opening = Scalar;
+
+    fn commit<RNG: CryptoRng + RngCore>(
+        msg: &[Scalar],
+        rng: &mut RNG,
+    ) -> (PedersenCommitment, Scalar) {
+        let opening = random_scalar(rng);
+        let commitment = with_generators(msg.len(), |g| vector_commitment(g, msg, &opening));
+        (commitment, opening)
+    }
+
+    fn verify(commitment: &PedersenCommitment, msg: &[Scalar], opening: &Scalar) -> Result<()> {
+        let commitment_ = with_generators(msg.len(), |g| vector_commitment(g, msg, opening));
+        check(commitment_ == *commitment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AesRng, Channel};
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_pedersen() {
+        let mut rng = AesRng::new();
+        let m = random_scalar(&mut rng);
+        let (c, r) = Pedersen::commit(&m, &mut rng);
+        assert!(Pedersen::verify(&c, &m, &r).is_ok());
+        assert!(Pedersen::verify(&c, &(m + Scalar::one()), &r).is_err());
+        assert!(Pedersen::verify(&c, &m, &(r + Scalar::one())).is_err());
+        // A vector commitment to one scalar is a Pedersen commitment.
+        assert!(VectorPedersen::verify(&c, &[m], &r).is_ok());
+    }
+
+    #[test]
+    fn test_homomorphic() {
+        let mut rng = AesRng::new();
+        let (m, m_) = (random_scalar(&mut rng), random_scalar(&mut rng));
+        let (c, r) = Pedersen::commit(&m, &mut rng);
+        let (c_, r_) = Pedersen::commit(&m_, &mut rng);
+        assert!(Pedersen::verify(&(c + c_), &(m + m_), &(r + r_)).is_ok());
+        assert!(Pedersen::verify(&(c - c_), &(m - m_), &(r - r_)).is_ok());
+        let k = random_scalar(&mut rng);
+        assert!(Pedersen::verify(&(c * k), &(k * m), &(k * r)).is_ok());
+    }
+
+    #[test]
+    fn test_vector() {
+        let mut rng = AesRng::new();
+        let generators = PedersenGenerators::new(8);
+        let msg = (0..8).map(|_| random_scalar(&mut rng)).collect::<Vec<_>>();
+        let (c, r) = generators.commit(&msg, &mut rng);
+        assert!(generators.verify(&c, &msg, &r).is_ok());
+        assert!(VectorPedersen::verify(&c, &msg, &r).is_ok());
+        assert!(generators.verify(&c, &msg[..7], &r).is_err());
+        assert!(PedersenGenerators::new(4).verify(&c, &msg, &r).is_err());
+        // The length of the message is bound.
+        let (c_, r_) = VectorPedersen::commit(&msg[..1], &mut rng);
+        assert!(VectorPedersen::verify(&c_, &msg[..1], &r_).is_ok());
+        let padded = [msg[0], Scalar::zero(), Scalar::zero()];
+        assert!(VectorPedersen::verify(&c_, &padded, &r_).is_err());
+    }
+
+    #[test]
+    fn test_batch_verify() {
+        let mut rng = AesRng::new();
+        let generators = PedersenGenerators::new(4);
+        let msgs = (0..10)
+            .map(|_| (0..4).map(|_| random_scalar(&mut rng)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let mut items = msgs
+            .iter()
+            .map(|msg| {
+                let (c, r) = generators.commit(msg, &mut rng);
+                (c, msg.as_slice(), r)
+            })
+            .collect::<Vec<_>>();
+        assert!(generators.batch_verify(&items, &mut rng).is_ok());
+        items[3].2 += Scalar::one();
+        assert!(generators.batch_verify(&items, &mut rng).is_err());
+        items[3].2 -= Scalar::one();
+        items[5].1 = &msgs[5][..3];
+        assert!(generators.batch_verify(&items, &mut rng).is_err());
+
+        let m = random_scalar(&mut rng);
+        let (c, r) = Pedersen::commit(&m, &mut rng);
+        assert!(Pedersen::batch_verify(&[(c, m, r), (c * m, m * m, r * m)], &mut rng).is_ok());
+        assert!(Pedersen::batch_verify(&[(c, m, r), (c, m, m)], &mut rng).is_err());
+    }
+
+    #[test]
+    fn test_channel() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut sender = Channel::new(a.try_clone().unwrap(), a);
+        let mut receiver = Channel::new(b.try_clone().unwrap(), b);
+        let mut rng = AesRng::new();
+        let m = random_scalar(&mut rng);
+        let (c, r) = Pedersen::commit(&m, &mut rng);
+        c.write(&mut sender).unwrap();
+        sender.write_scalar(&m).unwrap();
+        sender.write_scalar(&r).unwrap();
+        sender.flush().unwrap();
+        let c_ = PedersenCommitment::read(&mut receiver).unwrap();
+        let m_ = receiver.read_scalar().unwrap();
+        let r_ = receiver.read_scalar().unwrap();
+        assert!(Pedersen::verify(&c_, &m_, &r_).is_ok());
+        assert_eq!(PedersenCommitment::from_bytes(&c.to_bytes()), Some(c));
+    }
+}
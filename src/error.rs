@@ -0,0 +1,124 @@
+// -*- mode: rust; -*-
+//
+// This file is part of `scuttlebutt`.
+// Copyright © 2019 Galois, Inc.
+// See LICENSE for licensing information.
+
+//! The error type shared by channels, commitments and protocols.
+
+/// Errors produced by scuttlebutt.
+#[derive(Debug)]
+pub enum Error {
+    /// An I/O error occurred on the underlying transport, including timeouts
+    /// and cancellation.
+    IoError(std::io::Error),
+    /// Data read from a channel could not be decoded, e.g., an invalid point
+    /// encoding or a length over the limit.
+    DecodingError(String),
+    /// A check on data received from the peer failed, e.g., a commitment did
+    /// not open to the revealed value or a MAC was invalid.
+    VerificationFailed(String),
+    /// The value revealed for the commitment with index `index` did not match
+    /// it.
+    OpeningFailed {
+        /// The index of the first value that failed to open.
+        index: usize,
+    },
+    /// The party with index `party` cheated. If `relayer` is set, another
+    /// party received inconsistent commitments of `party` relayed by
+    /// `relayer`, and either of the two may be at fault.
+    CheatingParty {
+        /// The index of the party at fault.
+        party: usize,
+        /// The index of the party that relayed the disputed data, if any.
+        relayer: Option<usize>,
+    },
+    /// The peer aborted the protocol, as signalled by a framed channel (see
+    /// `AbstractChannel::abort`).
+    PeerAborted,
+    /// A channel or protocol was used incorrectly, e.g., the parties run
+    /// different versions of it or it is used after failing.
+    ProtocolMisuse(String),
+}
+
+/// A specialized `Result` type for scuttlebutt.
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    #[inline]
+    pub(crate) fn decoding(msg: impl ToString) -> Self {
+        Error::DecodingError(msg.to_string())
+    }
+
+    #[inline]
+    pub(crate) fn verification(msg: impl ToString) -> Self {
+        Error::VerificationFailed(msg.to_string())
+    }
+
+    #[inline]
+    pub(crate) fn misuse(msg: impl ToString) -> Self {
+        Error::ProtocolMisuse(msg.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::IoError(e)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::IoError(e) => write!(f, "IO error: {}", e),
+            Error::DecodingError(s) => write!(f, "decoding error: {}", s),
+            Error::VerificationFailed(s) => write!(f, "verification failed: {}", s),
+            Error::OpeningFailed { index } => {
+                write!(f, "value {} does not match its commitment", index)
+            }
+            Error::CheatingParty {
+                party,
+                relayer: None,
+            } => write!(f, "party {} cheated", party),
+            Error::CheatingParty {
+                party,
+                relayer: Some(relayer),
+            } => write!(
+                f,
+                "party {} or party {} cheated: commitments of party {} relayed inconsistently",
+                party, relayer, party
+            ),
+            Error::PeerAborted => "the peer aborted the protocol".fmt(f),
+            Error::ProtocolMisuse(s) => write!(f, "protocol misuse: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IoError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn test_source() {
+        let e = Error::from(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "eof",
+        ));
+        assert_eq!(e.to_string(), "IO error: eof");
+        let source = e.source().unwrap();
+        assert_eq!(source.to_string(), "eof");
+        let e = Error::verification("bad tag");
+        assert_eq!(e.to_string(), "verification failed: bad tag");
+        assert!(e.source().is_none());
+    }
+}
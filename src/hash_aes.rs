@@ -40,6 +40,16 @@ impl AesHash {
         self.aes.encrypt(x) ^ x
     }
 
+    /// Correlation-robust hash function `π(x) ⊕ x` on eight inputs at once.
+    #[inline]
+    pub fn cr_hash8(&self, x: [Block; 8]) -> [Block; 8] {
+        let mut y = self.aes.encrypt8(x);
+        for (y, x) in y.iter_mut().zip(x.iter()) {
+            *y ^= *x;
+        }
+        y
+    }
+
     /// Circular correlation-robust hash function (cf.
     /// <https://eprint.iacr.org/2019/074>, §7.3).
     ///
@@ -0,0 +1,357 @@
+// -*- mode: rust; -*-
+//
+// This file is part of `scuttlebutt`.
+// Copyright © 2019 Galois, Inc.
+// See LICENSE for licensing information.
+
+//! Long-term identity keys and Schnorr signatures over the Ristretto group,
+//! used to authenticate the parties at either end of a channel.
+//!
+//! A `Keypair` signs messages with a deterministic Schnorr signature: the
+//! nonce `k` is derived by hashing the secret key and message, and the
+//! signature is `(R, s) = (kG, k + cx)` with challenge `c = H(R, P, m)`.
+//! `authenticate` uses these signatures to bind both parties' identities to
+//! the keys of a `SecureChannel` session.
+
+use crate::{AbstractChannel, Error, Result, SecureChannel};
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use rand_core::{CryptoRng, RngCore};
+use sha2::{Digest, Sha512};
+use std::io::{Read, Write};
+use std::path::Path;
+
+const SECRET_KEY_MAGIC: [u8; 8] = *b"SCBTSK01";
+const PUBLIC_KEY_MAGIC: [u8; 8] = *b"SCBTPK01";
+
+// Hash `label` and `parts` to a scalar.
+fn hash_to_scalar(label: &[u8], parts: &[&[u8]]) -> Scalar {
+    let mut hash = Sha512::new();
+    hash.input(label);
+    for part in parts.iter() {
+        hash.input(part);
+    }
+    let mut wide = [0u8; 64];
+    wide.copy_from_slice(&hash.result());
+    Scalar::from_bytes_mod_order_wide(&wide)
+}
+
+#[inline]
+fn challenge(r: &CompressedRistretto, public: &PublicKey, message: &[u8]) -> Scalar {
+    hash_to_scalar(
+        b"scuttlebutt schnorr challenge",
+        &[r.as_bytes(), public.0.compress().as_bytes(), message],
+    )
+}
+
+// Parse a canonical scalar encoding.
+#[inline]
+fn scalar_from_bytes(bytes: [u8; 32]) -> Option<Scalar> {
+    let scalar = Scalar::from_bytes_mod_order(bytes);
+    if scalar.to_bytes() == bytes {
+        Some(scalar)
+    } else {
+        None
+    }
+}
+
+// Read a key file with the given magic, returning its 32-byte payload.
+fn read_key_file<P: AsRef<Path>>(path: P, magic: &[u8; 8]) -> Result<[u8; 32]> {
+    let data = std::fs::read(path)?;
+    if data.len() != 40 || &data[..8] != magic {
+        return Err(Error::decoding("not a scuttlebutt key file"));
+    }
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&data[8..]);
+    Ok(bytes)
+}
+
+// Write a new key file, readable and writable only by its owner on Unix.
+fn write_key_file<P: AsRef<Path>>(path: P, magic: &[u8; 8], bytes: &[u8; 32]) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(magic)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// A Schnorr signature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Signature {
+    r: CompressedRistretto,
+    s: Scalar,
+}
+
+impl Signature {
+    /// Encode the signature as bytes.
+    pub fn to_bytes(&self) -> [u8; 64] {
+        let mut bytes = [0u8; 64];
+        bytes[..32].copy_from_slice(self.r.as_bytes());
+        bytes[32..].copy_from_slice(self.s.as_bytes());
+        bytes
+    }
+
+    /// Decode a signature produced by `to_bytes`, returning `None` if the
+    /// encoding is invalid.
+    pub fn from_bytes(bytes: &[u8; 64]) -> Option<Self> {
+        let mut r = [0u8; 32];
+        let mut s = [0u8; 32];
+        r.copy_from_slice(&bytes[..32]);
+        s.copy_from_slice(&bytes[32..]);
+        Some(Self {
+            r: CompressedRistretto(r),
+            s: scalar_from_bytes(s)?,
+        })
+    }
+}
+
+/// A public identity key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PublicKey(RistrettoPoint);
+
+impl PublicKey {
+    /// Verify `signature` on `message`.
+    pub fn verify(&self, message: &[u8], signature: &Signature) -> bool {
+        let r = match signature.r.decompress() {
+            Some(r) => r,
+            None => return false,
+        };
+        let c = challenge(&signature.r, self, message);
+        RISTRETTO_BASEPOINT_POINT * signature.s == r + self.0 * c
+    }
+
+    /// Encode the key as bytes.
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.compress().to_bytes()
+    }
+
+    /// Decode a key produced by `to_bytes`, returning `None` if the encoding
+    /// is invalid.
+    pub fn from_bytes(bytes: &[u8; 32]) -> Option<Self> {
+        CompressedRistretto(*bytes).decompress().map(PublicKey)
+    }
+
+    /// Save the key to the file at `path`, which must not exist yet.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        write_key_file(path, &PUBLIC_KEY_MAGIC, &self.to_bytes())
+    }
+
+    /// Load a key saved by `save` from the file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let bytes = read_key_file(path, &PUBLIC_KEY_MAGIC)?;
+        Self::from_bytes(&bytes).ok_or_else(|| Error::decoding("invalid public key"))
+    }
+}
+
+/// A long-term identity key: a secret key and its public key.
+#[derive(Clone)]
+pub struct Keypair {
+    secret: Scalar,
+    public: PublicKey,
+}
+
+impl Keypair {
+    /// Generate a new key pair using randomness from `rng`.
+    pub fn generate<RNG: CryptoRng + RngCore>(rng: &mut RNG) -> Self {
+        let mut seed = [0u8; 64];
+        rng.fill_bytes(&mut seed);
+        Self::from_secret(Scalar::from_bytes_mod_order_wide(&seed))
+    }
+
+    #[inline]
+    fn from_secret(secret: Scalar) -> Self {
+        let public = PublicKey(RISTRETTO_BASEPOINT_POINT * secret);
+        Self { secret, public }
+    }
+
+    /// Return the public key.
+    pub fn public(&self) -> PublicKey {
+        self.public
+    }
+
+    /// Sign `message`.
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        let k = hash_to_scalar(
+            b"scuttlebutt schnorr nonce",
+            &[self.secret.as_bytes(), message],
+        );
+        let r = (RISTRETTO_BASEPOINT_POINT * k).compress();
+        let c = challenge(&r, &self.public, message);
+        Signature {
+            r,
+            s: k + c * self.secret,
+        }
+    }
+
+    /// Encode the secret key as bytes.
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.secret.to_bytes()
+    }
+
+    /// Decode a secret key produced by `to_bytes`, returning `None` if the
+    /// encoding is invalid.
+    pub fn from_bytes(bytes: &[u8; 32]) -> Option<Self> {
+        scalar_from_bytes(*bytes).map(Self::from_secret)
+    }
+
+    /// Save the secret key to the file at `path`, which must not exist yet.
+    /// On Unix, the file is only accessible to its owner; it must be kept
+    /// private.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        write_key_file(path, &SECRET_KEY_MAGIC, &self.to_bytes())
+    }
+
+    /// Load a key pair saved by `save` from the file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let bytes = read_key_file(path, &SECRET_KEY_MAGIC)?;
+        Self::from_bytes(&bytes).ok_or_else(|| Error::decoding("invalid secret key"))
+    }
+}
+
+impl std::fmt::Debug for Keypair {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Keypair")
+            .field("public", &self.public)
+            .finish()
+    }
+}
+
+/// Authenticate the parties at either end of `channel`, returning the other
+/// party's public key.
+///
+/// Each party sends its public key over the channel, along with its signature
+/// on the channel's key exchange transcript (see `SecureChannel::transcript`)
+/// and its own public key. As the transcript covers both ephemeral keys of
+/// the key exchange, a man-in-the-middle running a separate key exchange with
+/// each party cannot pass the signatures on, so the returned key is that of
+/// the party holding the session keys. The caller should check it against the
+/// identities it expects. Fails with `VerificationFailed` if the other party's
+/// signature does not verify.
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+pub fn authenticate<R, W>(channel: &mut SecureChannel<R, W>, keypair: &Keypair) -> Result<PublicKey>
+where
+    R: Read,
+    W: Write,
+{
+    let transcript = channel.transcript();
+    let message = |public: &PublicKey| {
+        let mut message = b"scuttlebutt authentication".to_vec();
+        message.extend_from_slice(&transcript);
+        message.extend_from_slice(&public.to_bytes());
+        message
+    };
+    channel.write_pt(&keypair.public.0)?;
+    channel.write_bytes(&keypair.sign(&message(&keypair.public)).to_bytes())?;
+    channel.flush()?;
+    let peer = PublicKey(channel.read_pt()?);
+    let mut signature = [0u8; 64];
+    channel.read_bytes(&mut signature)?;
+    if peer == keypair.public {
+        return Err(channel.reject(Error::verification("peer is using our identity key")));
+    }
+    match Signature::from_bytes(&signature) {
+        Some(signature) if peer.verify(&message(&peer), &signature) => Ok(peer),
+        _ => Err(channel.reject(Error::verification(
+            "peer's signature on the session transcript is invalid",
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AesRng;
+    use std::io::BufReader;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let keypair = Keypair::generate(&mut AesRng::new());
+        let signature = keypair.sign(b"hello world");
+        assert!(keypair.public().verify(b"hello world", &signature));
+        assert!(!keypair.public().verify(b"hello world!", &signature));
+        let other = Keypair::generate(&mut AesRng::new());
+        assert!(!other.public().verify(b"hello world", &signature));
+        let signature_ = Signature::from_bytes(&signature.to_bytes()).unwrap();
+        assert_eq!(signature, signature_);
+    }
+
+    #[test]
+    fn test_key_files() {
+        let dir = std::env::temp_dir();
+        let secret = dir.join(format!("scuttlebutt-test-{}.sk", std::process::id()));
+        let public = dir.join(format!("scuttlebutt-test-{}.pk", std::process::id()));
+        let keypair = Keypair::generate(&mut AesRng::new());
+        keypair.save(&secret).unwrap();
+        keypair.public().save(&public).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&secret).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        // Existing files are not overwritten.
+        assert!(keypair.save(&public).is_err());
+        let keypair_ = Keypair::load(&secret).unwrap();
+        assert_eq!(keypair_.public(), keypair.public());
+        assert_eq!(PublicKey::load(&public).unwrap(), keypair.public());
+        let err = PublicKey::load(&secret).unwrap_err();
+        assert!(matches!(err, Error::DecodingError(_)));
+        std::fs::remove_file(secret).unwrap();
+        std::fs::remove_file(public).unwrap();
+    }
+
+    fn secure_channel(stream: UnixStream) -> SecureChannel<BufReader<UnixStream>, UnixStream> {
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        SecureChannel::new(reader, stream, &mut AesRng::new()).unwrap()
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let alice = Keypair::generate(&mut AesRng::new());
+        let bob = Keypair::generate(&mut AesRng::new());
+        let (alice_, bob_) = (alice.public(), bob.public());
+        let (a, b) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut channel = secure_channel(a);
+            authenticate(&mut channel, &alice).unwrap()
+        });
+        let mut channel = secure_channel(b);
+        assert_eq!(authenticate(&mut channel, &bob).unwrap(), alice_);
+        assert_eq!(handle.join().unwrap(), bob_);
+    }
+
+    #[test]
+    fn test_man_in_the_middle() {
+        let (a, m) = UnixStream::pair().unwrap();
+        let (m_, b) = UnixStream::pair().unwrap();
+        let parties = vec![a, b]
+            .into_iter()
+            .map(|stream| {
+                std::thread::spawn(move || {
+                    let keypair = Keypair::generate(&mut AesRng::new());
+                    authenticate(&mut secure_channel(stream), &keypair)
+                })
+            })
+            .collect::<Vec<_>>();
+        // Run a key exchange with each party and relay their authentication
+        // messages unchanged.
+        let (mut m, mut m_) = (secure_channel(m), secure_channel(m_));
+        let (mut message, mut message_) = ([0u8; 96], [0u8; 96]);
+        m.read_bytes(&mut message).unwrap();
+        m_.read_bytes(&mut message_).unwrap();
+        m_.write_bytes(&message).unwrap();
+        m_.flush().unwrap();
+        m.write_bytes(&message_).unwrap();
+        m.flush().unwrap();
+        for party in parties {
+            let result = party.join().unwrap();
+            assert!(matches!(result, Err(Error::VerificationFailed(_))));
+        }
+    }
+}
@@ -19,8 +19,12 @@ mod block;
 mod block512;
 mod channel;
 pub mod cointoss;
+pub mod commit_open;
 pub mod commitment;
+mod error;
 mod hash_aes;
+#[cfg(feature = "curve25519-dalek")]
+pub mod identity;
 mod rand_aes;
 pub mod utils;
 
@@ -28,7 +32,20 @@ pub use crate::aes::aes128::{Aes128, FIXED_KEY_AES128};
 pub use crate::aes::aes256::Aes256;
 pub use crate::block::Block;
 pub use crate::block512::Block512;
-pub use crate::channel::{AbstractChannel, Channel, SyncChannel, HashChannel, TrackChannel};
+#[cfg(feature = "serde")]
+pub use crate::channel::AbstractChannelExt;
+#[cfg(feature = "curve25519-dalek")]
+pub use crate::channel::SecureChannel;
+#[cfg(all(unix, feature = "shm"))]
+pub use crate::channel::ShmChannel;
+pub use crate::channel::{
+    read_transcript, AbstractChannel, CancelHandle, Channel, ChannelStats, CostChannel, Fault,
+    FaultChannel, HashChannel, NetworkConfig, ReadHalf, RecordChannel, ReplayChannel,
+    SessionChannel, SimChannel, SyncChannel, SyncReadHalf, SyncTrackChannel, SyncWriteHalf,
+    TrackChannel, TranscriptDirection, TranscriptEntry, WriteHalf, DEFAULT_MAX_VEC_LEN,
+    DEFAULT_PHASE, WIRE_FORMAT_VERSION,
+};
+pub use crate::error::{Error, Result};
 pub use crate::hash_aes::{AesHash, AES_HASH};
 pub use crate::rand_aes::AesRng;
 
@@ -52,6 +52,12 @@ pub fn and_inplace(a: &mut [u8], b: &[u8]) {
     }
 }
 
+/// Compare two byte arrays in time independent of their contents.
+#[inline]
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;